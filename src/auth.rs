@@ -0,0 +1,309 @@
+use super::*;
+use hyper::header::{self, HeaderValue};
+use serde::Deserialize;
+use std::time::SystemTime;
+
+/// Tokens are refreshed this long before they expire, so requests in flight don't carry an expired one.
+const EXPIRY_MARGIN: Duration = Duration::from_secs(10);
+
+/// How often --bearer-file is checked for changes, instead of on every request.
+const FILE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where bearer tokens come from, see --bearer, --bearer-file and --oauth2-token-url.
+#[derive(Debug, Clone)]
+pub enum BearerConfig {
+    Token(String),
+    /// Read again when the file changed, checked at most once a second.
+    File(String),
+    OAuth2(OAuth2Config),
+}
+
+/// OAuth2 client credentials grant.
+#[derive(Debug, Clone)]
+pub struct OAuth2Config {
+    pub token_url: Url,
+    pub client_id: String,
+    pub client_secret: String,
+    pub scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    /// Seconds, tokens without an expiry are only refreshed after a 401.
+    expires_in: Option<u64>,
+}
+
+pub struct FileToken {
+    modified: Option<SystemTime>,
+    /// When the modification time was last looked at.
+    checked_at: Option<Instant>,
+    value: Option<HeaderValue>,
+}
+
+pub struct OAuth2Token {
+    value: HeaderValue,
+    expires_at: Option<Instant>,
+}
+
+/// Provides the `Authorization: Bearer` header of every request.
+pub enum Bearer {
+    Static(HeaderValue),
+    File {
+        path: String,
+        token: std::sync::Mutex<FileToken>,
+    },
+    OAuth2 {
+        config: OAuth2Config,
+        /// Held while fetching, so requests waiting for a token share one token request.
+        token: tokio::sync::Mutex<Option<OAuth2Token>>,
+    },
+}
+
+impl Bearer {
+    /// Reads the token file or fetches a first OAuth2 token, failing when the token can't be had.
+    pub async fn new(config: &BearerConfig, client: &client::HttpClient) -> Result<Self, String> {
+        let bearer = match config {
+            BearerConfig::Token(token) => Bearer::Static(bearer_header(token)?),
+            BearerConfig::File(path) => Bearer::File {
+                path: path.clone(),
+                token: std::sync::Mutex::new(FileToken {
+                    modified: None,
+                    checked_at: None,
+                    value: None,
+                }),
+            },
+            BearerConfig::OAuth2(config) => Bearer::OAuth2 {
+                token: tokio::sync::Mutex::new(Some(fetch_token(config, client).await?)),
+                config: config.clone(),
+            },
+        };
+
+        if let Bearer::File { path, token } = &bearer {
+            read_token_file(path, token)?;
+        }
+
+        Ok(bearer)
+    }
+
+    /// The header to send, `None` when no token could be had, in which case the error is logged.
+    pub async fn header(&self, client: &client::HttpClient) -> Option<HeaderValue> {
+        let result = match self {
+            Bearer::Static(value) => Ok(value.clone()),
+            Bearer::File { path, token } => read_token_file(path, token),
+            Bearer::OAuth2 { config, token } => {
+                let mut token = token.lock().await;
+
+                match token.as_ref() {
+                    Some(token)
+                        if token
+                            .expires_at
+                            .is_none_or(|expires_at| Instant::now() < expires_at) =>
+                    {
+                        Ok(token.value.clone())
+                    }
+                    _ => fetch_token(config, client).await.map(|fetched| {
+                        let value = fetched.value.clone();
+                        *token = Some(fetched);
+                        value
+                    }),
+                }
+            }
+        };
+
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                error!("{}", e.red());
+                None
+            }
+        }
+    }
+
+    /// True when a 401 response is worth retrying with a new token.
+    pub fn refreshes(&self) -> bool {
+        matches!(self, Bearer::OAuth2 { .. })
+    }
+
+    /// Drops the token after a 401 so the next request fetches a new one. Tokens other than `used`
+    /// were already refreshed by another request.
+    pub async fn reject(&self, used: &HeaderValue) {
+        if let Bearer::OAuth2 { token, .. } = self {
+            let mut token = token.lock().await;
+
+            if token.as_ref().map(|token| &token.value) == Some(used) {
+                *token = None;
+            }
+        }
+    }
+}
+
+/// The token in the file at `path`, only read again when the file was modified. The modification
+/// time is looked at once per `FILE_CHECK_INTERVAL`, so requests don't all wait for the file system.
+fn read_token_file(path: &str, token: &std::sync::Mutex<FileToken>) -> Result<HeaderValue, String> {
+    let mut token = token.lock().unwrap();
+
+    if let (Some(value), Some(checked_at)) = (&token.value, token.checked_at) {
+        if checked_at.elapsed() < FILE_CHECK_INTERVAL {
+            return Ok(value.clone());
+        }
+    }
+
+    token.checked_at = Some(Instant::now());
+    let modified = std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .map_err(|e| format!("{e} : {path}"))?;
+
+    match &token.value {
+        Some(value) if token.modified == Some(modified) => Ok(value.clone()),
+        _ => {
+            let content = std::fs::read_to_string(path).map_err(|e| format!("{e} : {path}"))?;
+            let value = bearer_header(content.trim())?;
+
+            token.modified = Some(modified);
+            token.value = Some(value.clone());
+
+            Ok(value)
+        }
+    }
+}
+
+fn bearer_header(token: &str) -> Result<HeaderValue, String> {
+    let mut value = HeaderValue::from_str(&format!("Bearer {token}"))
+        .map_err(|_| "Invalid bearer token, it can't contain control characters".to_string())?;
+    value.set_sensitive(true);
+
+    Ok(value)
+}
+
+/// Requests a token from the token endpoint, authenticating the client with basic auth.
+async fn fetch_token(
+    config: &OAuth2Config,
+    client: &client::HttpClient,
+) -> Result<OAuth2Token, String> {
+    let body = url::form_urlencoded::Serializer::new(String::new())
+        .append_pair("grant_type", "client_credentials")
+        .extend_pairs(config.scope.iter().map(|scope| ("scope", scope)))
+        .finish();
+
+    let mut headers = hyper::header::HeaderMap::new();
+    headers.insert(
+        header::AUTHORIZATION,
+        client::basic_auth(&config.client_id, Some(&config.client_secret)),
+    );
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-www-form-urlencoded"),
+    );
+    headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+
+    let requested_at = Instant::now();
+    let failed = |e: String| format!("OAuth2 token request to {} failed: {e}", config.token_url);
+
+    let response = client
+        .send(
+            client::Request {
+                method: hyper::Method::POST,
+                url: config.token_url.clone(),
+                headers,
+                body: body.into(),
+            },
+            None,
+        )
+        .await
+        .map_err(|e| failed(e.to_string()))?;
+
+    if !response.status.is_success() {
+        return Err(failed(format!(
+            "{} {}",
+            response.status,
+            String::from_utf8_lossy(&response.body)
+        )));
+    }
+
+    let token: TokenResponse =
+        serde_json::from_slice(&response.body).map_err(|e| failed(e.to_string()))?;
+
+    Ok(OAuth2Token {
+        value: bearer_header(&token.access_token)?,
+        expires_at: token.expires_in.map(|expires_in| {
+            requested_at + Duration::from_secs(expires_in).saturating_sub(EXPIRY_MARGIN)
+        }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bearer, BearerConfig, OAuth2Config};
+    use crate::test_server::RawServer;
+
+    #[tokio::test]
+    async fn rereads_token_files() {
+        let path = std::env::temp_dir().join(format!("verdun-token-{}", std::process::id()));
+        std::fs::write(&path, "first\n").unwrap();
+
+        let client = crate::client::HttpClient::builder().build().unwrap();
+        let config = BearerConfig::File(path.to_string_lossy().to_string());
+        let bearer = Bearer::new(&config, &client).await.unwrap();
+        assert_eq!(bearer.header(&client).await.unwrap(), "Bearer first");
+
+        // Make sure the change shows even with a coarse modification time.
+        std::fs::write(&path, "second").unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(std::time::SystemTime::now() + std::time::Duration::from_secs(5))
+            .unwrap();
+
+        // The file was just checked.
+        assert_eq!(bearer.header(&client).await.unwrap(), "Bearer first");
+
+        if let Bearer::File { token, .. } = &bearer {
+            token.lock().unwrap().checked_at = None;
+        }
+        assert_eq!(bearer.header(&client).await.unwrap(), "Bearer second");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn refreshes_rejected_oauth2_tokens() {
+        let server = RawServer::tcp(std::time::Duration::ZERO, |index| {
+            let body = format!(
+                r#"{{"access_token": "t{}", "expires_in": 3600}}"#,
+                index + 1
+            );
+            format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+        })
+        .await;
+
+        let client = crate::client::HttpClient::builder().build().unwrap();
+        let config = BearerConfig::OAuth2(OAuth2Config {
+            token_url: format!("{}token", server.url).parse().unwrap(),
+            client_id: "id".to_string(),
+            client_secret: "secret".to_string(),
+            scope: Some("read".to_string()),
+        });
+
+        let bearer = Bearer::new(&config, &client).await.unwrap();
+        let first = bearer.header(&client).await.unwrap();
+        assert_eq!(first, "Bearer t1");
+
+        bearer.reject(&first).await;
+        assert_eq!(bearer.header(&client).await.unwrap(), "Bearer t2");
+
+        // A stale rejection doesn't drop the new token.
+        bearer.reject(&first).await;
+        assert_eq!(bearer.header(&client).await.unwrap(), "Bearer t2");
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[0].starts_with("POST /token "));
+        assert!(requests[0].contains("authorization: Basic aWQ6c2VjcmV0"));
+        assert!(requests[0].ends_with("grant_type=client_credentials&scope=read"));
+    }
+}
@@ -0,0 +1,225 @@
+use super::*;
+use rand::Rng;
+use template::{Parser, Template};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MultipartValue {
+    Text(Template),
+    File { filename: String, content: Vec<u8> },
+}
+
+/// Request body configured with --body, --body-file, --form or --multipart.
+#[derive(Debug, Clone)]
+pub enum RequestBody {
+    /// --body, or a UTF-8 --body-file.
+    Text(Template),
+    Binary(Vec<u8>),
+    /// `application/x-www-form-urlencoded` fields.
+    Form(Vec<(Template, Template)>),
+    Multipart {
+        boundary: String,
+        parts: Vec<(String, MultipartValue)>,
+    },
+}
+
+impl RequestBody {
+    /// Content type sent along with the body unless a Content-Type header is set explicitly.
+    pub fn content_type(&self) -> Option<String> {
+        match self {
+            RequestBody::Text(_) | RequestBody::Binary(_) => None,
+            RequestBody::Form(_) => Some("application/x-www-form-urlencoded".to_string()),
+            RequestBody::Multipart { boundary, .. } => {
+                Some(format!("multipart/form-data; boundary={boundary}"))
+            }
+        }
+    }
+
+    /// True when every request gets the same body, so it can be encoded once.
+    pub fn is_static(&self) -> bool {
+        match self {
+            RequestBody::Text(template) => template.is_static(),
+            RequestBody::Binary(_) => true,
+            RequestBody::Form(fields) => fields
+                .iter()
+                .all(|(name, value)| name.is_static() && value.is_static()),
+            RequestBody::Multipart { parts, .. } => parts.iter().all(|(_, value)| match value {
+                MultipartValue::Text(template) => template.is_static(),
+                MultipartValue::File { .. } => true,
+            }),
+        }
+    }
+
+    /// Encodes the body, rendering every template with `render`. File contents are sent as is.
+    pub fn encode(&self, render: impl Fn(&Template) -> String) -> Vec<u8> {
+        match self {
+            RequestBody::Text(template) => render(template).into_bytes(),
+            RequestBody::Binary(content) => content.clone(),
+            RequestBody::Form(fields) => url::form_urlencoded::Serializer::new(String::new())
+                .extend_pairs(
+                    fields
+                        .iter()
+                        .map(|(name, value)| (render(name), render(value))),
+                )
+                .finish()
+                .into_bytes(),
+            RequestBody::Multipart { boundary, parts } => {
+                let mut out = vec![];
+
+                for (name, value) in parts.iter() {
+                    out.extend_from_slice(format!("--{boundary}\r\n").as_bytes());
+
+                    match value {
+                        MultipartValue::Text(text) => {
+                            out.extend_from_slice(
+                                format!(
+                                    "Content-Disposition: form-data; name=\"{}\"\r\n\r\n",
+                                    quote(name)
+                                )
+                                .as_bytes(),
+                            );
+                            out.extend_from_slice(render(text).as_bytes());
+                        }
+                        MultipartValue::File { filename, content } => {
+                            out.extend_from_slice(
+                                format!(
+                                    "Content-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                                    quote(name),
+                                    quote(filename)
+                                )
+                                .as_bytes(),
+                            );
+                            out.extend_from_slice(content);
+                        }
+                    }
+
+                    out.extend_from_slice(b"\r\n");
+                }
+
+                out.extend_from_slice(format!("--{boundary}--\r\n").as_bytes());
+                out
+            }
+        }
+    }
+}
+
+/// --body-file contents, templates are only parsed in UTF-8 files.
+pub fn from_file(content: Vec<u8>, parser: &Parser) -> Result<RequestBody, String> {
+    match String::from_utf8(content) {
+        Ok(text) => Ok(RequestBody::Text(parser.parse(&text)?)),
+        Err(e) => Ok(RequestBody::Binary(e.into_bytes())),
+    }
+}
+
+/// Parses `key=value` --form fields.
+pub fn parse_form(fields: &[String], parser: &Parser) -> Result<RequestBody, String> {
+    fields
+        .iter()
+        .map(|field| match field.split_once('=') {
+            Some((name, value)) if !name.is_empty() => {
+                Ok((parser.parse(name)?, parser.parse(value)?))
+            }
+            _ => Err(format!("Invalid form field, should be key=value: {field}")),
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(RequestBody::Form)
+}
+
+/// Parses --multipart fields, `name=@path` uploads the file at path and `name=value` sends text.
+pub fn parse_multipart(fields: &[String], parser: &Parser) -> Result<RequestBody, String> {
+    let parts = fields
+        .iter()
+        .map(|field| {
+            let (name, value) = match field.split_once('=') {
+                Some((name, value)) if !name.is_empty() => (name.to_string(), value),
+                _ => {
+                    return Err(format!(
+                        "Invalid multipart field, should be name=@file or name=value: {field}"
+                    ))
+                }
+            };
+
+            match value.strip_prefix('@') {
+                Some(path) => {
+                    let content = std::fs::read(path).map_err(|e| format!("{e} : {path}"))?;
+                    let filename = std::path::Path::new(path)
+                        .file_name()
+                        .map(|name| name.to_string_lossy().to_string())
+                        .unwrap_or_else(|| path.to_string());
+
+                    Ok((name, MultipartValue::File { filename, content }))
+                }
+                None => Ok((name, MultipartValue::Text(parser.parse(value)?))),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(RequestBody::Multipart {
+        boundary: format!(
+            "----{}Boundary{:016x}",
+            APP_NAME,
+            rand::thread_rng().gen::<u64>()
+        ),
+        parts,
+    })
+}
+
+fn quote(value: &str) -> String {
+    value.replace('"', "%22").replace(['\r', '\n'], " ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MultipartValue, RequestBody};
+    use crate::template::{Parser, RenderContext, Template};
+
+    fn render(template: &Template) -> String {
+        template.render(&RenderContext {
+            worker_id: 7,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn encodes_forms_and_multipart() {
+        let parser = Parser {
+            enabled: true,
+            ..Default::default()
+        };
+        let form = super::parse_form(
+            &["a=1 2".to_string(), "b=x&y%WORKER_ID%".to_string()],
+            &parser,
+        )
+        .unwrap();
+        assert!(!form.is_static());
+        assert_eq!(form.encode(render), b"a=1+2&b=x%26y7");
+        assert!(super::parse_form(&["novalue".to_string()], &Parser::default()).is_err());
+
+        let multipart = RequestBody::Multipart {
+            boundary: "XX".to_string(),
+            parts: vec![
+                (
+                    "field".to_string(),
+                    MultipartValue::Text(Template::literal("v%WORKER_ID%")),
+                ),
+                (
+                    "upload".to_string(),
+                    MultipartValue::File {
+                        filename: "a.bin".to_string(),
+                        content: vec![0, 1],
+                    },
+                ),
+            ],
+        };
+
+        let mut expected = b"--XX\r\nContent-Disposition: form-data; name=\"field\"\r\n\r\nv%WORKER_ID%\r\n--XX\r\nContent-Disposition: form-data; name=\"upload\"; filename=\"a.bin\"\r\nContent-Type: application/octet-stream\r\n\r\n".to_vec();
+        expected.extend_from_slice(&[0, 1]);
+        expected.extend_from_slice(b"\r\n--XX--\r\n");
+
+        assert!(multipart.is_static());
+        assert_eq!(multipart.encode(render), expected);
+        assert_eq!(
+            multipart.content_type().unwrap(),
+            "multipart/form-data; boundary=XX"
+        );
+    }
+}
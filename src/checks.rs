@@ -0,0 +1,251 @@
+use super::*;
+use json_path::JsonPath;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Checks as written in the --expect-* and --max-* options, or in the `checks` of a scenario step.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RawChecks {
+    pub status: Option<String>,
+    #[serde(default)]
+    pub body: Vec<String>,
+    #[serde(default)]
+    pub body_regex: Vec<String>,
+    #[serde(default)]
+    pub json: Vec<String>,
+    #[serde(default)]
+    pub header: Vec<String>,
+    pub max_body_size: Option<usize>,
+    /// Milliseconds.
+    pub max_latency: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum StatusMatch {
+    Exact(u16),
+    /// `2xx`, stored as the hundreds digit.
+    Class(u16),
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Status(Vec<StatusMatch>),
+    BodyContains(String),
+    BodyRegex(regex::Regex),
+    JsonEquals(JsonPath, Value),
+    HeaderPresent(hyper::header::HeaderName),
+    MaxBodySize(usize),
+    MaxLatency(Duration),
+}
+
+/// A condition responses must meet. Failed checks are counted apart from transport errors.
+#[derive(Debug, Clone)]
+pub struct Check {
+    /// How the check is reported, ex: `status in 200,201`.
+    pub name: String,
+    condition: Condition,
+}
+
+impl Check {
+    pub fn passes(&self, response: &client::Response) -> bool {
+        match &self.condition {
+            Condition::Status(expected) => {
+                let status = response.status.as_u16();
+
+                expected.iter().any(|expected| match expected {
+                    StatusMatch::Exact(code) => status == *code,
+                    StatusMatch::Class(class) => status / 100 == *class,
+                })
+            }
+            Condition::BodyContains(text) => {
+                String::from_utf8_lossy(&response.body).contains(text.as_str())
+            }
+            Condition::BodyRegex(regex) => regex.is_match(&String::from_utf8_lossy(&response.body)),
+            Condition::JsonEquals(path, expected) => {
+                serde_json::from_slice::<Value>(&response.body)
+                    .ok()
+                    .and_then(|json| path.select(&json).cloned())
+                    .as_ref()
+                    == Some(expected)
+            }
+            Condition::HeaderPresent(name) => response.headers.contains_key(name),
+            Condition::MaxBodySize(size) => response.body.len() <= *size,
+            Condition::MaxLatency(latency) => response.duration <= *latency,
+        }
+    }
+
+    /// Names the check after a scenario step, so per-step checks are told apart in the report.
+    pub fn in_step(self, step: &str) -> Self {
+        Check {
+            name: format!("{step}: {}", self.name),
+            ..self
+        }
+    }
+}
+
+/// Names of the checks the response failed.
+pub fn failed<'a>(
+    checks: impl Iterator<Item = &'a Check>,
+    response: &client::Response,
+) -> Vec<String> {
+    checks
+        .filter(|check| !check.passes(response))
+        .map(|check| check.name.clone())
+        .collect()
+}
+
+impl RawChecks {
+    pub fn compile(&self) -> Result<Vec<Check>, String> {
+        let mut checks = vec![];
+
+        if let Some(status) = self.status.as_ref() {
+            checks.push(Check {
+                name: format!("status in {status}"),
+                condition: Condition::Status(parse_status(status)?),
+            });
+        }
+
+        for text in self.body.iter() {
+            checks.push(Check {
+                name: format!("body contains {text:?}"),
+                condition: Condition::BodyContains(text.clone()),
+            });
+        }
+
+        for regex in self.body_regex.iter() {
+            checks.push(Check {
+                name: format!("body matches /{regex}/"),
+                condition: Condition::BodyRegex(
+                    regex::Regex::new(regex).map_err(|e| e.to_string())?,
+                ),
+            });
+        }
+
+        for expression in self.json.iter() {
+            let (path, expected) = expression
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid JSON check, should be path=value: {expression}"))?;
+            let (path, expected) = (path.trim(), expected.trim());
+
+            checks.push(Check {
+                name: format!("json {path} == {expected}"),
+                condition: Condition::JsonEquals(
+                    JsonPath::parse(path)?,
+                    // Anything that isn't valid JSON is compared as a string.
+                    serde_json::from_str(expected)
+                        .unwrap_or_else(|_| Value::String(expected.to_string())),
+                ),
+            });
+        }
+
+        for header in self.header.iter() {
+            checks.push(Check {
+                name: format!("header {header} present"),
+                condition: Condition::HeaderPresent(
+                    hyper::header::HeaderName::from_bytes(header.as_bytes())
+                        .map_err(|e| format!("{e} : {header}"))?,
+                ),
+            });
+        }
+
+        if let Some(size) = self.max_body_size {
+            checks.push(Check {
+                name: format!("body size <= {size} bytes"),
+                condition: Condition::MaxBodySize(size),
+            });
+        }
+
+        if let Some(latency) = self.max_latency {
+            checks.push(Check {
+                name: format!("latency <= {latency}ms"),
+                condition: Condition::MaxLatency(Duration::from_millis(latency)),
+            });
+        }
+
+        Ok(checks)
+    }
+}
+
+/// Parses a comma separated list of status codes and classes such as `200,201,3xx`.
+fn parse_status(input: &str) -> Result<Vec<StatusMatch>, String> {
+    input
+        .split(',')
+        .map(|status| {
+            let status = status.trim().to_lowercase();
+
+            let parsed = match status.strip_suffix("xx") {
+                Some(class) => class.parse::<u16>().ok().map(StatusMatch::Class),
+                None => status.parse::<u16>().ok().map(StatusMatch::Exact),
+            };
+
+            match parsed {
+                Some(StatusMatch::Class(class)) if (1..=5).contains(&class) => {
+                    Ok(StatusMatch::Class(class))
+                }
+                Some(StatusMatch::Exact(code)) if (100..600).contains(&code) => {
+                    Ok(StatusMatch::Exact(code))
+                }
+                _ => Err(format!("Invalid expected status: {status}")),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RawChecks;
+    use std::time::Duration;
+
+    #[test]
+    fn checks_responses() {
+        let checks = RawChecks {
+            status: Some("201, 3xx".to_string()),
+            body: vec!["id=1".to_string()],
+            body_regex: vec!["id=\\d+".to_string()],
+            json: vec!["$.ok = true".to_string(), "$.name=verdun".to_string()],
+            header: vec!["X-Request-Id".to_string()],
+            max_body_size: Some(40),
+            max_latency: Some(100),
+        }
+        .compile()
+        .unwrap();
+
+        let mut response = crate::client::Response {
+            status: hyper::StatusCode::FOUND,
+            body: r#"{"ok": true, "name": "verdun"}"#.into(),
+            duration: Duration::from_millis(150),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            super::failed(checks.iter(), &response),
+            vec![
+                "body contains \"id=1\"",
+                "body matches /id=\\d+/",
+                "header X-Request-Id present",
+                "latency <= 100ms"
+            ]
+        );
+
+        response.status = hyper::StatusCode::OK;
+        response
+            .headers
+            .insert("x-request-id", "1".parse().unwrap());
+        response.duration = Duration::from_millis(10);
+        response.body = r#"{"ok": true, "name": "verdun", "id=1": "ok"}"#.into();
+        assert_eq!(
+            super::failed(checks.iter(), &response),
+            vec!["status in 201, 3xx", "body size <= 40 bytes"]
+        );
+
+        assert!(super::parse_status("200,6xx").is_err());
+        assert!(super::parse_status("abc").is_err());
+        assert!(RawChecks {
+            json: vec!["$.ok".to_string()],
+            ..Default::default()
+        }
+        .compile()
+        .is_err());
+    }
+}
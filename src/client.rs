@@ -0,0 +1,971 @@
+use super::*;
+use connector::{ConnectionCounts, ConnectionInfo, Connector};
+use hyper::body::Bytes;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use hyper::{Method, StatusCode};
+use stats::ErrorKind;
+use std::error::Error as StdError;
+use std::io::Read;
+
+/// What to do with a redirect response, see `ClientBuilder::redirect`.
+pub enum RedirectAction {
+    Follow,
+    /// Return the redirect response as is.
+    Stop,
+    Error,
+}
+
+/// Decides whether to follow a redirect to the given URL, `previous` holds every URL requested so far.
+pub type RedirectPolicy = Arc<dyn Fn(&Url, &[Url]) -> RedirectAction + Send + Sync>;
+
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    source: Box<dyn StdError + Send + Sync>,
+}
+
+impl Error {
+    fn new(kind: ErrorKind, source: impl Into<Box<dyn StdError + Send + Sync>>) -> Self {
+        Error {
+            kind,
+            source: source.into(),
+        }
+    }
+
+    fn from_hyper(error: hyper::Error) -> Self {
+        let kind = if is_timeout(&error) {
+            ErrorKind::Timeout
+        } else if error.is_connect() {
+            ErrorKind::Connect
+        } else {
+            ErrorKind::Request
+        };
+
+        Error::new(kind, error)
+    }
+
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} error: {}", self.kind.as_str(), self.source)
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        Some(self.source.as_ref())
+    }
+}
+
+fn is_timeout(error: &(dyn StdError + 'static)) -> bool {
+    let mut source = Some(error);
+
+    while let Some(error) = source {
+        if let Some(io) = error.downcast_ref::<std::io::Error>() {
+            if io.kind() == std::io::ErrorKind::TimedOut {
+                return true;
+            }
+        }
+
+        source = error.source();
+    }
+
+    false
+}
+
+/// Where the time of a request went. The phases add up to the total time of the request.
+#[derive(Debug, Clone, Default)]
+pub struct Timings {
+    /// DNS, connect and TLS are only set for requests that had to open a new connection.
+    pub dns: Option<Duration>,
+    pub connect: Option<Duration>,
+    pub tls: Option<Duration>,
+    /// From the connection being ready until the response headers arrived, so it covers sending
+    /// the request, server think time, any redirects that were followed and any 401 response
+    /// that was answered with new credentials.
+    pub ttfb: Duration,
+    /// Time spent reading the response body.
+    pub download: Duration,
+}
+
+impl Timings {
+    fn add_connection(&mut self, info: &ConnectionInfo) {
+        let add = |phase: Option<Duration>, time: Duration| Some(phase.unwrap_or_default() + time);
+
+        if let Some(dns) = info.dns {
+            self.dns = add(self.dns, dns);
+        }
+
+        self.connect = add(self.connect, info.connect);
+
+        if let Some(tls) = info.tls {
+            self.tls = add(self.tls, tls);
+        }
+    }
+
+    /// Adds the DNS, connect and TLS time of `other`.
+    fn add_setup(&mut self, other: &Timings) {
+        let add = |phase: Option<Duration>, other: Option<Duration>| match (phase, other) {
+            (None, None) => None,
+            (phase, other) => Some(phase.unwrap_or_default() + other.unwrap_or_default()),
+        };
+
+        self.dns = add(self.dns, other.dns);
+        self.connect = add(self.connect, other.connect);
+        self.tls = add(self.tls, other.tls);
+    }
+
+    fn connection_setup(&self) -> Duration {
+        [self.dns, self.connect, self.tls]
+            .iter()
+            .flatten()
+            .sum::<Duration>()
+    }
+}
+
+#[derive(Clone)]
+pub struct Request {
+    pub method: Method,
+    pub url: Url,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+#[derive(Default)]
+pub struct Response {
+    pub status: StatusCode,
+    /// HTTP version of the final response.
+    pub version: hyper::Version,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    /// Time until the response headers arrived.
+    pub duration: Duration,
+    pub timings: Timings,
+    /// Hops of the request sent on a connection opened earlier.
+    pub connections_reused: u32,
+}
+
+/// Which HTTP versions to use, see --http1-only, --http2 and --http2-prior-knowledge.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HttpVersion {
+    /// HTTP/2 when the server picks it with ALPN, otherwise HTTP/1.1.
+    Negotiate,
+    Http1Only,
+    /// Require HTTP/2 over TLS through ALPN, plain HTTP stays on HTTP/1.1.
+    Http2,
+    /// HTTP/2 on every connection without negotiating it, h2c for plain HTTP.
+    Http2PriorKnowledge,
+}
+
+impl Response {
+    /// Folds `earlier`, a response to the same request that was answered by sending it again,
+    /// into this one. `waited` is the time from sending `earlier` until this request was sent, so
+    /// the duration covers both round trips.
+    pub fn retry_of(mut self, earlier: &Response, waited: Duration) -> Self {
+        self.duration += waited;
+        self.timings.add_setup(&earlier.timings);
+        self.timings.ttfb = self
+            .duration
+            .saturating_sub(self.timings.connection_setup());
+        self.connections_reused += earlier.connections_reused;
+        self
+    }
+}
+
+pub struct ClientBuilder {
+    redirect: Option<RedirectPolicy>,
+    http_version: HttpVersion,
+    tls: tls::TlsOptions,
+    overrides: resolve::Overrides,
+    round_robin: bool,
+    proxies: proxy::Proxies,
+    keepalive: bool,
+    max_connections_per_host: Option<usize>,
+    pool_idle_timeout: Duration,
+    #[cfg(unix)]
+    unix_socket: Option<std::path::PathBuf>,
+    user_agent: Option<HeaderValue>,
+    connect_timeout: Duration,
+    timeout: Duration,
+    compression: bool,
+}
+
+impl ClientBuilder {
+    /// Follow redirects according to `policy`. Without a policy redirect responses are returned as is.
+    pub fn redirect(mut self, policy: Option<RedirectPolicy>) -> Self {
+        self.redirect = policy;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: &str) -> Self {
+        self.user_agent = HeaderValue::from_str(user_agent).ok();
+        self
+    }
+
+    pub fn http_version(mut self, http_version: HttpVersion) -> Self {
+        self.http_version = http_version;
+        self
+    }
+
+    /// Trusted CAs, client certificate and verification of HTTPS connections.
+    pub fn tls(mut self, options: tls::TlsOptions) -> Self {
+        self.tls = options;
+        self
+    }
+
+    /// --resolve and --connect-to mappings applied when opening connections.
+    pub fn overrides(mut self, overrides: resolve::Overrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    /// Spread new connections to a host over all of its addresses instead of preferring the first.
+    pub fn round_robin(mut self, enabled: bool) -> Self {
+        self.round_robin = enabled;
+        self
+    }
+
+    /// Send requests through these proxies.
+    pub fn proxies(mut self, proxies: proxy::Proxies) -> Self {
+        self.proxies = proxies;
+        self
+    }
+
+    /// Keep connections open to reuse them for later requests. Without keep-alive every request
+    /// opens a new connection, including the TLS handshake.
+    pub fn keepalive(mut self, enabled: bool) -> Self {
+        self.keepalive = enabled;
+        self
+    }
+
+    /// Cap on the connections open at once to the same scheme, host and port. Requests wait for a
+    /// free connection when it's reached.
+    pub fn max_connections_per_host(mut self, max: Option<usize>) -> Self {
+        self.max_connections_per_host = max;
+        self
+    }
+
+    /// How long an unused connection is kept open for reuse.
+    pub fn pool_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.pool_idle_timeout = timeout;
+        self
+    }
+
+    /// Send every request over this unix socket, whatever the host of its URL.
+    #[cfg(unix)]
+    pub fn unix_socket(mut self, path: Option<std::path::PathBuf>) -> Self {
+        self.unix_socket = path;
+        self
+    }
+
+    /// Limit for DNS resolution, TCP connect and TLS handshake together.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// Limit for a whole request, from sending it until its body has been read.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Ask for gzip/deflate compressed responses and decompress them.
+    pub fn compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    pub fn build(self) -> anyhow::Result<HttpClient> {
+        // Fall back to public resolvers when the system configuration can't be read.
+        let resolver = match trust_dns_resolver::TokioAsyncResolver::tokio_from_system_conf() {
+            Ok(resolver) => resolver,
+            Err(_) => trust_dns_resolver::TokioAsyncResolver::tokio(
+                Default::default(),
+                Default::default(),
+            )?,
+        };
+
+        let tls = self
+            .tls
+            .client_config(match self.http_version {
+                HttpVersion::Negotiate => vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+                HttpVersion::Http1Only => vec![b"http/1.1".to_vec()],
+                HttpVersion::Http2 | HttpVersion::Http2PriorKnowledge => vec![b"h2".to_vec()],
+            })
+            .map_err(anyhow::Error::msg)?;
+
+        let proxies = Arc::new(self.proxies);
+
+        let connector = Connector::new(resolver, tls, self.connect_timeout)
+            .require_h2(self.http_version == HttpVersion::Http2)
+            .overrides(self.overrides)
+            .round_robin(self.round_robin)
+            .proxies(proxies.clone())
+            .max_connections_per_host(self.max_connections_per_host);
+        #[cfg(unix)]
+        let connector = connector.unix_socket(self.unix_socket);
+
+        Ok(HttpClient {
+            connector: connector.clone(),
+            client: hyper::Client::builder()
+                .http2_only(self.http_version == HttpVersion::Http2PriorKnowledge)
+                .pool_idle_timeout(self.pool_idle_timeout)
+                .pool_max_idle_per_host(match self.keepalive {
+                    true => self.max_connections_per_host.unwrap_or(usize::MAX),
+                    false => 0,
+                })
+                .build(connector),
+            redirect: self.redirect,
+            proxies,
+            user_agent: self.user_agent,
+            timeout: self.timeout,
+            compression: self.compression,
+        })
+    }
+}
+
+/// HTTP client built on hyper with our own connector, so every request reports its `Timings`.
+pub struct HttpClient {
+    /// A clone of the connector used by `client`, sharing its counts.
+    connector: Connector,
+    client: hyper::Client<Connector, hyper::Body>,
+    redirect: Option<RedirectPolicy>,
+    proxies: Arc<proxy::Proxies>,
+    user_agent: Option<HeaderValue>,
+    timeout: Duration,
+    compression: bool,
+}
+
+impl HttpClient {
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder {
+            redirect: None,
+            http_version: HttpVersion::Negotiate,
+            tls: tls::TlsOptions::default(),
+            overrides: resolve::Overrides::default(),
+            round_robin: false,
+            proxies: proxy::Proxies::default(),
+            keepalive: true,
+            max_connections_per_host: None,
+            pool_idle_timeout: Duration::from_secs(90),
+            #[cfg(unix)]
+            unix_socket: None,
+            user_agent: None,
+            connect_timeout: Duration::from_secs(10),
+            timeout: Duration::from_secs(30),
+            compression: true,
+        }
+    }
+
+    /// Connections opened so far, including those of requests that failed.
+    pub fn connections(&self) -> ConnectionCounts {
+        self.connector.counts()
+    }
+
+    /// Sends `request`, with the cookies of `cookies` if given. Cookies set by the response and
+    /// any redirect along the way are stored in it.
+    pub async fn send(
+        &self,
+        request: Request,
+        cookies: Option<&cookies::Jar>,
+    ) -> Result<Response, Error> {
+        self.send_signed(request, cookies, &[]).await
+    }
+
+    /// Like `send`, with `signers` signing every hop of the request. Redirects to another host
+    /// go out without the headers of the signers.
+    pub async fn send_signed(
+        &self,
+        request: Request,
+        cookies: Option<&cookies::Jar>,
+        signers: &[Arc<dyn signers::Signer>],
+    ) -> Result<Response, Error> {
+        match tokio::time::timeout(self.timeout, self.execute(request, cookies, signers)).await {
+            Ok(response) => response,
+            Err(_) => Err(Error::new(
+                ErrorKind::Timeout,
+                std::io::Error::new(std::io::ErrorKind::TimedOut, "request timed out"),
+            )),
+        }
+    }
+
+    async fn execute(
+        &self,
+        mut request: Request,
+        cookies: Option<&cookies::Jar>,
+        signers: &[Arc<dyn signers::Signer>],
+    ) -> Result<Response, Error> {
+        let started = Instant::now();
+        let mut timings = Timings::default();
+        let mut connections_reused = 0;
+        let mut previous = vec![];
+        let mut sign = true;
+
+        let response = loop {
+            if sign {
+                for signer in signers {
+                    signer.sign(&mut request);
+                }
+            }
+
+            let response = self
+                .client
+                .request(self.build_request(&request, cookies)?)
+                .await
+                .map_err(Error::from_hyper)?;
+
+            if let Some(cookies) = cookies {
+                cookies.store(&request.url, response.headers());
+            }
+
+            if let Some(info) = response.extensions().get::<Arc<ConnectionInfo>>() {
+                if info.claim() {
+                    timings.add_connection(info);
+                } else {
+                    connections_reused += 1;
+                }
+            }
+
+            match self.redirect_request(&request, &response, &mut previous, signers)? {
+                Some(next) => {
+                    // Read to the end, otherwise the connection can't go back to the pool.
+                    let _ = hyper::body::to_bytes(response.into_body()).await;
+                    sign = same_origin(&request.url, &next.url);
+                    request = next;
+                }
+                None => break response,
+            }
+        };
+
+        let duration = started.elapsed();
+        timings.ttfb = duration.saturating_sub(timings.connection_setup());
+
+        let (mut parts, body) = response.into_parts();
+
+        let download_started = Instant::now();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(|e| Error::new(ErrorKind::Body, e))?;
+        timings.download = download_started.elapsed();
+
+        let body = match self.compression {
+            true => decode(&mut parts.headers, body)?,
+            false => body,
+        };
+
+        Ok(Response {
+            status: parts.status,
+            version: parts.version,
+            headers: parts.headers,
+            body,
+            duration,
+            timings,
+            connections_reused,
+        })
+    }
+
+    fn build_request(
+        &self,
+        request: &Request,
+        cookies: Option<&cookies::Jar>,
+    ) -> Result<hyper::Request<hyper::Body>, Error> {
+        let mut built = hyper::Request::builder()
+            .method(request.method.clone())
+            .uri(request.url.as_str())
+            .body(hyper::Body::from(request.body.clone()))
+            .map_err(|e| Error::new(ErrorKind::Request, e))?;
+
+        let headers = built.headers_mut();
+        *headers = request.headers.clone();
+
+        if let Some(cookies) = cookies {
+            cookies.add_cookie_header(&request.url, headers);
+        }
+
+        // Plain HTTP requests are forwarded by HTTP proxies, HTTPS ones authenticate the CONNECT.
+        if request.url.scheme() == "http" {
+            if let Some(authorization) = self
+                .proxies
+                .get(false, request.url.host_str().unwrap_or_default())
+                .and_then(|proxy| proxy.authorization())
+            {
+                headers.insert(header::PROXY_AUTHORIZATION, authorization);
+            }
+        }
+
+        if let Some(user_agent) = self.user_agent.as_ref() {
+            if !headers.contains_key(header::USER_AGENT) {
+                headers.insert(header::USER_AGENT, user_agent.clone());
+            }
+        }
+
+        if self.compression && !headers.contains_key(header::ACCEPT_ENCODING) {
+            headers.insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_static("gzip, deflate"),
+            );
+        }
+
+        Ok(built)
+    }
+
+    /// The request to send next when `response` is a redirect that should be followed.
+    fn redirect_request(
+        &self,
+        request: &Request,
+        response: &hyper::Response<hyper::Body>,
+        previous: &mut Vec<Url>,
+        signers: &[Arc<dyn signers::Signer>],
+    ) -> Result<Option<Request>, Error> {
+        let policy = match self.redirect.as_ref() {
+            Some(policy) => policy,
+            None => return Ok(None),
+        };
+
+        let status = response.status();
+        if !matches!(status.as_u16(), 301 | 302 | 303 | 307 | 308) {
+            return Ok(None);
+        }
+
+        let location = match response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| request.url.join(location).ok())
+        {
+            Some(location) => location,
+            None => return Ok(None),
+        };
+
+        previous.push(request.url.clone());
+
+        match policy(&location, previous) {
+            RedirectAction::Follow => {}
+            RedirectAction::Stop => return Ok(None),
+            RedirectAction::Error => {
+                return Err(Error::new(
+                    ErrorKind::Redirect,
+                    format!("too many redirects, last location: {location}"),
+                ))
+            }
+        }
+
+        let mut headers = request.headers.clone();
+
+        // Signatures only hold for the request they were made for, the next hop gets its own.
+        for name in signers.iter().flat_map(|signer| signer.headers()) {
+            headers.remove(name);
+        }
+
+        // Credentials are only meant for the host they were configured for.
+        if !same_origin(&location, &request.url) {
+            headers.remove(header::AUTHORIZATION);
+            headers.remove(header::COOKIE);
+            headers.remove(header::PROXY_AUTHORIZATION);
+        }
+
+        // 301/302/303 switch to a body-less GET the way browsers do, 307/308 replay the request.
+        let (method, body) = match status.as_u16() {
+            307 | 308 => (request.method.clone(), request.body.clone()),
+            _ if request.method == Method::HEAD => (Method::HEAD, Bytes::new()),
+            _ => {
+                headers.remove(header::CONTENT_TYPE);
+                headers.remove(header::CONTENT_LENGTH);
+                (Method::GET, Bytes::new())
+            }
+        };
+
+        Ok(Some(Request {
+            method,
+            url: location,
+            headers,
+            body,
+        }))
+    }
+}
+
+fn same_origin(a: &Url, b: &Url) -> bool {
+    a.host_str() == b.host_str() && a.port_or_known_default() == b.port_or_known_default()
+}
+
+/// Decompresses gzip/deflate bodies, removing the headers that describe the encoded body.
+fn decode(headers: &mut HeaderMap, body: Bytes) -> Result<Bytes, Error> {
+    let encoding = headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|encoding| encoding.to_str().ok())
+        .map(|encoding| encoding.trim().to_ascii_lowercase());
+
+    let mut decoded = vec![];
+
+    let result = match encoding.as_deref() {
+        _ if body.is_empty() => return Ok(body),
+        Some("gzip") => flate2::read::GzDecoder::new(&body[..]).read_to_end(&mut decoded),
+        Some("deflate") => flate2::read::ZlibDecoder::new(&body[..]).read_to_end(&mut decoded),
+        _ => return Ok(body),
+    };
+
+    result.map_err(|e| Error::new(ErrorKind::Decode, e))?;
+
+    headers.remove(header::CONTENT_ENCODING);
+    headers.remove(header::CONTENT_LENGTH);
+
+    Ok(Bytes::from(decoded))
+}
+
+/// `Authorization` header value for HTTP basic auth.
+pub fn basic_auth(username: &str, password: Option<&str>) -> HeaderValue {
+    let credentials = base64::encode(format!("{}:{}", username, password.unwrap_or_default()));
+    let mut value = HeaderValue::from_str(&format!("Basic {credentials}")).unwrap();
+    value.set_sensitive(true);
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_server::{ok, RawServer};
+    use hyper::body::Bytes;
+    use hyper::header::{self, HeaderMap, HeaderValue};
+    use std::io::Write;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn decodes_gzip_bodies() {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(b"hello world").unwrap();
+        let body = Bytes::from(encoder.finish().unwrap());
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        headers.insert(header::CONTENT_LENGTH, HeaderValue::from(body.len()));
+
+        let decoded = super::decode(&mut headers, body).unwrap();
+
+        assert_eq!(&decoded[..], b"hello world");
+        assert!(headers.is_empty());
+
+        let mut headers = HeaderMap::new();
+        let plain = super::decode(&mut headers, Bytes::from_static(b"plain")).unwrap();
+        assert_eq!(&plain[..], b"plain");
+    }
+
+    fn get(url: &str) -> super::Request {
+        super::Request {
+            method: hyper::Method::GET,
+            url: url.parse().unwrap(),
+            headers: HeaderMap::new(),
+            body: Bytes::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn times_request_phases() {
+        let server = RawServer::tcp(Duration::from_millis(50), |_| ok("ok")).await;
+        let client = super::HttpClient::builder().build().unwrap();
+
+        let response = client.send(get(&server.url), None).await.unwrap();
+        assert_eq!(response.status, hyper::StatusCode::OK);
+        assert_eq!(&response.body[..], b"ok");
+
+        // No lookup for IP address hosts and no handshake for plain HTTP.
+        let timings = response.timings;
+        assert_eq!(timings.dns, None);
+        assert!(timings.connect.is_some());
+        assert_eq!(timings.tls, None);
+        assert!(timings.ttfb >= Duration::from_millis(50));
+
+        // The connection is kept alive, so the next request only has a TTFB.
+        let response = client.send(get(&server.url), None).await.unwrap();
+        assert_eq!(response.timings.connect, None);
+        assert!(response.timings.ttfb >= Duration::from_millis(50));
+        assert_eq!(server.connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn limits_connections() {
+        // Slow enough that concurrent requests need more than one connection.
+        let server = RawServer::tcp(Duration::from_millis(20), |_| ok("ok")).await;
+        let client = super::HttpClient::builder()
+            .max_connections_per_host(Some(1))
+            .build()
+            .unwrap();
+
+        let responses =
+            futures::future::join_all((0..4).map(|_| client.send(get(&server.url), None))).await;
+        let responses = responses
+            .into_iter()
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+
+        assert_eq!(server.connections(), 1);
+        assert_eq!(
+            responses.iter().map(|r| r.connections_reused).sum::<u32>(),
+            3
+        );
+        assert_eq!(client.connections().opened, 1);
+
+        // Waiting for a free connection counts towards the connect timeout.
+        let server = RawServer::tcp(Duration::from_millis(300), |_| ok("ok")).await;
+        let client = super::HttpClient::builder()
+            .max_connections_per_host(Some(1))
+            .connect_timeout(Duration::from_millis(50))
+            .build()
+            .unwrap();
+
+        let (first, second) = futures::future::join(
+            client.send(get(&server.url), None),
+            client.send(get(&server.url), None),
+        )
+        .await;
+        assert!(first.is_ok() != second.is_ok());
+        let error = first.err().or(second.err()).unwrap();
+        assert_eq!(error.kind(), super::ErrorKind::Timeout);
+
+        let server = RawServer::tcp(Duration::ZERO, |_| ok("ok")).await;
+        let client = super::HttpClient::builder()
+            .keepalive(false)
+            .build()
+            .unwrap();
+
+        for _ in 0..3 {
+            let response = client.send(get(&server.url), None).await.unwrap();
+            assert_eq!(response.connections_reused, 0);
+        }
+        assert_eq!(server.connections(), 3);
+        assert_eq!(client.connections().opened, 3);
+    }
+
+    #[tokio::test]
+    async fn reuses_connections_across_redirects() {
+        // Large enough that the body isn't read along with the headers.
+        let moved = "x".repeat(256 * 1024);
+        let server = RawServer::tcp(Duration::ZERO, move |index| match index % 2 {
+            0 => format!(
+                "HTTP/1.1 302 Found\r\nLocation: /next\r\nContent-Length: {}\r\n\r\n{moved}",
+                moved.len()
+            ),
+            _ => ok("ok"),
+        })
+        .await;
+        let client = super::HttpClient::builder()
+            .redirect(Some(Arc::new(|_: &url::Url, _: &[url::Url]| {
+                super::RedirectAction::Follow
+            })))
+            .build()
+            .unwrap();
+
+        let response = client.send(get(&server.url), None).await.unwrap();
+        assert_eq!(&response.body[..], b"ok");
+        assert_eq!(response.connections_reused, 1);
+
+        let response = client.send(get(&server.url), None).await.unwrap();
+        assert_eq!(response.connections_reused, 2);
+
+        assert_eq!(server.connections(), 1);
+        assert_eq!(client.connections().opened, 1);
+        assert!(server.requests()[1].starts_with("GET /next HTTP/1.1\r\n"));
+    }
+
+    #[tokio::test]
+    async fn signs_every_redirect_hop() {
+        let other = RawServer::tcp(Duration::ZERO, |_| ok("ok")).await;
+        let other_url = format!("{}other", other.url);
+        let server = RawServer::tcp(Duration::ZERO, move |index| match index {
+            0 => "HTTP/1.1 302 Found\r\nLocation: /next\r\nContent-Length: 0\r\n\r\n".to_string(),
+            _ => {
+                format!("HTTP/1.1 302 Found\r\nLocation: {other_url}\r\nContent-Length: 0\r\n\r\n")
+            }
+        })
+        .await;
+        let client = super::HttpClient::builder()
+            .redirect(Some(Arc::new(|_: &url::Url, _: &[url::Url]| {
+                super::RedirectAction::Follow
+            })))
+            .build()
+            .unwrap();
+
+        let signer = crate::signers::HmacSigner::new(
+            "key",
+            "secret",
+            header::HeaderName::from_static("x-signature"),
+        );
+        let signers: Vec<Arc<dyn crate::signers::Signer>> = vec![Arc::new(signer)];
+        client
+            .send_signed(get(&server.url), None, &signers)
+            .await
+            .unwrap();
+
+        let requests = server.requests();
+        let header = |request: &str, name: &str| {
+            request
+                .lines()
+                .find_map(|line| line.strip_prefix(&format!("{name}: ")))
+                .map(str::to_string)
+        };
+
+        // The same host gets a signature for the new path.
+        let mut next = get(&format!("{}next", server.url));
+        next.headers.insert(
+            header::DATE,
+            header(&requests[1], "date").unwrap().parse().unwrap(),
+        );
+        signers[0].sign(&mut next);
+        assert_eq!(
+            header(&requests[1], "x-signature").unwrap(),
+            next.headers["x-signature"]
+        );
+        assert_ne!(
+            header(&requests[0], "x-signature"),
+            header(&requests[1], "x-signature")
+        );
+
+        // The other port is another origin, which gets no signature at all.
+        let other = &other.requests()[0];
+        assert!(other.starts_with("GET /other HTTP/1.1\r\n"));
+        assert_eq!(header(other, "x-signature"), None);
+        assert_eq!(header(other, "x-content-sha256"), None);
+    }
+
+    #[tokio::test]
+    async fn counts_connections_of_failed_requests() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+
+        // Accepts the connection and never answers.
+        tokio::spawn(async move {
+            let (_stream, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let client = super::HttpClient::builder()
+            .timeout(Duration::from_millis(100))
+            .build()
+            .unwrap();
+
+        assert!(client.send(get(&url), None).await.is_err());
+        assert_eq!(client.connections().opened, 1);
+        assert!(client.connections().tls.is_empty());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn sends_over_unix_sockets() {
+        let path = std::env::temp_dir().join(format!("verdun-{}.sock", std::process::id()));
+        let server = RawServer::unix(&path, |_| ok("ok"));
+
+        let client = super::HttpClient::builder()
+            .unix_socket(Some(path.clone()))
+            .build()
+            .unwrap();
+        let response = client
+            .send(get("http://app.local/health"), None)
+            .await
+            .unwrap();
+        assert_eq!(&response.body[..], b"ok");
+        assert_eq!(client.connections().opened, 1);
+
+        let request = &server.requests()[0];
+        assert!(request.starts_with("GET /health HTTP/1.1\r\n"));
+        assert!(request.contains("host: app.local\r\n"));
+
+        assert!(client
+            .send(get("https://app.local/health"), None)
+            .await
+            .is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn negotiates_http_versions() {
+        use super::HttpVersion::*;
+        use hyper::Version;
+
+        let send = |http_version, url: String| async move {
+            super::HttpClient::builder()
+                .http_version(http_version)
+                .tls(crate::tls::TlsOptions {
+                    roots: Some(crate::test_server::certificates(
+                        crate::test_server::CA_CERT,
+                    )),
+                    ..Default::default()
+                })
+                .build()
+                .unwrap()
+                .send(get(&url), None)
+                .await
+        };
+
+        let url = format!("http://{}/", crate::test_server::http_server().await);
+        for (http_version, expected) in [
+            (Negotiate, Version::HTTP_11),
+            (Http1Only, Version::HTTP_11),
+            (Http2, Version::HTTP_11),
+            (Http2PriorKnowledge, Version::HTTP_2),
+        ] {
+            let response = send(http_version, url.clone()).await.unwrap();
+            assert_eq!(response.version, expected);
+            assert_eq!(response.body, format!("{expected:?}"));
+        }
+
+        let address = crate::test_server::https_server(&[b"h2", b"http/1.1"]).await;
+        let url = format!("https://localhost:{}/", address.port());
+        for (http_version, expected) in [
+            (Negotiate, Version::HTTP_2),
+            (Http1Only, Version::HTTP_11),
+            (Http2, Version::HTTP_2),
+        ] {
+            let response = send(http_version, url.clone()).await.unwrap();
+            assert_eq!(response.version, expected);
+        }
+
+        // --http2 fails connections on which the server doesn't pick HTTP/2, here a server
+        // that ignores ALPN.
+        let address = crate::test_server::https_server(&[]).await;
+        let url = format!("https://localhost:{}/", address.port());
+        let response = send(Negotiate, url.clone()).await.unwrap();
+        assert_eq!(response.version, Version::HTTP_11);
+
+        let error = send(Http2, url).await.err().unwrap();
+        assert_eq!(error.kind(), crate::stats::ErrorKind::Connect);
+        assert!(format!("{error:?}").contains("did not negotiate HTTP/2"));
+    }
+
+    #[tokio::test]
+    async fn connects_to_ip_hosts_over_tls() {
+        let address = crate::test_server::https_server(&[b"http/1.1"]).await;
+        let url = format!("https://127.0.0.1:{}/", address.port());
+
+        // The certificate is checked against the IP address, or not at all with --insecure.
+        for tls in [
+            crate::tls::TlsOptions {
+                roots: Some(crate::test_server::certificates(
+                    crate::test_server::CA_CERT,
+                )),
+                ..Default::default()
+            },
+            crate::tls::TlsOptions {
+                insecure: true,
+                ..Default::default()
+            },
+        ] {
+            let client = super::HttpClient::builder().tls(tls).build().unwrap();
+            let response = client.send(get(&url), None).await.unwrap();
+            assert_eq!(response.status, hyper::StatusCode::OK);
+        }
+    }
+
+    #[test]
+    fn basic_auth_header() {
+        assert_eq!(
+            super::basic_auth("user", Some("pass")),
+            "Basic dXNlcjpwYXNz"
+        );
+        assert_eq!(super::basic_auth("user", None), "Basic dXNlcjo=");
+    }
+}
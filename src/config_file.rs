@@ -0,0 +1,165 @@
+use super::*;
+use serde_json::{Map, Value};
+
+/// Reads a TOML test definition, or YAML when the path ends in `.yaml` or `.yml`.
+/// Keys are the long command line options, written with `_` or `-`.
+pub fn load(path: &str) -> Result<Map<String, Value>, String> {
+    read(path)
+        .and_then(parse)
+        .map_err(|e| format!("Invalid config file {path}: {e}"))
+}
+
+/// Reads a TOML file, or YAML when the path ends in `.yaml` or `.yml`, into a JSON value.
+pub fn read(path: &str) -> Result<Value, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("{e} : {path}"))?;
+    let lower = path.to_lowercase();
+
+    match lower.ends_with(".yaml") || lower.ends_with(".yml") {
+        true => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        false => toml::from_str(&content).map_err(|e| e.to_string()),
+    }
+}
+
+fn parse(value: Value) -> Result<Map<String, Value>, String> {
+    match value {
+        Value::Object(options) => options
+            .into_iter()
+            .map(|(key, value)| normalize(key.replace('-', "_"), value))
+            .collect(),
+        Value::Null => Ok(Map::new()),
+        _ => Err("expected a table of options".to_string()),
+    }
+}
+
+/// Accepts friendlier forms than the command line: a `headers` table, `stages` as a list of
+/// `{ duration, target }` tables, a numeric `rate` and `thresholds` for `threshold`.
+fn normalize(key: String, value: Value) -> Result<(String, Value), String> {
+    match (key.as_str(), value) {
+        ("headers", Value::Object(headers)) => Ok((
+            "header".to_string(),
+            Value::Array(
+                headers
+                    .into_iter()
+                    .map(|(name, value)| Value::String(format!("{name}:{}", text(&value))))
+                    .collect(),
+            ),
+        )),
+        ("stages", Value::Array(stages)) => {
+            let stages = stages
+                .iter()
+                .map(|stage| match (stage.get("duration"), stage.get("target")) {
+                    (Some(duration), Some(target)) => {
+                        Ok(format!("{}:{}", text(duration), text(target)))
+                    }
+                    _ => Err("stages should be tables with a duration and a target".to_string()),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+
+            Ok((key, Value::String(stages.join(","))))
+        }
+        ("thresholds", value) => Ok(("threshold".to_string(), value)),
+        ("rate", Value::Number(rate)) => Ok((key, Value::String(rate.to_string()))),
+        (_, value) => Ok((key, value)),
+    }
+}
+
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Applies file options to those parsed from the command line, except for options that were
+/// given on the command line.
+pub fn merge(
+    options: Map<String, Value>,
+    cli: &config::RawConfig,
+    matches: &clap::ArgMatches,
+) -> Result<config::RawConfig, String> {
+    let mut merged = match serde_json::to_value(cli) {
+        Ok(Value::Object(merged)) => merged,
+        _ => unreachable!("options serialize to a map"),
+    };
+
+    for (key, value) in options {
+        if !merged.contains_key(&key) {
+            return Err(format!("Unknown option in config file: {key}"));
+        }
+
+        // Argument ids are the field names in kebab case.
+        if matches.occurrences_of(key.replace('_', "-").as_str()) == 0 {
+            merged.insert(key, value);
+        }
+    }
+
+    serde_json::from_value(Value::Object(merged)).map_err(|e| format!("Invalid config file: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Cli, Mode};
+    use clap::{FromArgMatches, IntoApp};
+
+    #[test]
+    fn merges_file_and_command_line() {
+        let file = super::parse(
+            toml::from_str(
+                r#"
+url = "https://example.com"
+mode = "single"
+concurrent = 10
+rate = 50
+timeout-connect = 2000
+
+[headers]
+X-Api-Key = "secret"
+
+[[stages]]
+duration = "30s"
+target = 10
+"#,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        let matches = Cli::into_app().get_matches_from([
+            "verdun",
+            "run",
+            "test.toml",
+            "-c",
+            "4",
+            "--verbose",
+        ]);
+        let cli = Cli::from_arg_matches(&matches).unwrap();
+        let options = match cli.command {
+            Some(crate::config::Command::Run { options, .. }) => options,
+            _ => panic!("Expected run command"),
+        };
+
+        let run_matches = matches.subcommand_matches("run").unwrap();
+
+        let merged = super::merge(file, &options, run_matches).unwrap();
+        assert_eq!(merged.url.as_deref(), Some("https://example.com"));
+        assert_eq!(merged.mode, Mode::Single);
+        assert_eq!(merged.concurrent, 4);
+        assert!(merged.verbose);
+        assert_eq!(merged.timeout_connect, 2000);
+        assert_eq!(merged.rate.as_deref(), Some("50"));
+        assert_eq!(merged.stages.as_deref(), Some("30s:10"));
+        assert_eq!(merged.header, Some(vec!["X-Api-Key:secret".to_string()]));
+
+        let yaml = super::parse(
+            serde_yaml::from_str("method: post\nbody: '{\"id\": 1}'\nconcurrent: 8\n").unwrap(),
+        )
+        .unwrap();
+        let merged = super::merge(yaml, &options, run_matches).unwrap();
+        assert_eq!(merged.concurrent, 4);
+        assert_eq!(merged.method, crate::config::Method::POST);
+        assert_eq!(merged.body.as_deref(), Some("{\"id\": 1}"));
+
+        let unknown = super::parse(toml::from_str("concurency = 4").unwrap()).unwrap();
+        assert!(super::merge(unknown, &options, run_matches).is_err());
+    }
+}
@@ -0,0 +1,569 @@
+use super::*;
+use hyper::client::connect::{Connected, Connection as HyperConnection};
+use hyper::Uri;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::client::TlsStream;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// How a connection was established, shared by every request it serves.
+#[derive(Debug)]
+pub struct ConnectionInfo {
+    /// `None` when the host is an IP address or set with --resolve, so no lookup happened.
+    pub dns: Option<Duration>,
+    /// TCP connect, including the CONNECT or SOCKS5 handshake through a proxy.
+    pub connect: Duration,
+    /// `None` for plain HTTP connections.
+    pub tls: Option<Duration>,
+    requests: AtomicU64,
+}
+
+impl ConnectionInfo {
+    /// True for the first request served by the connection, which is the one that waited for it
+    /// to be established and should be charged for DNS, connect and TLS time.
+    pub fn claim(&self) -> bool {
+        self.requests.fetch_add(1, Ordering::Relaxed) == 0
+    }
+}
+
+/// Connections opened by a `Connector` and its clones. They are counted as soon as they are
+/// established, so connections of requests that time out or fail later are included.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionCounts {
+    pub opened: u64,
+    /// TLS handshakes by negotiated version and cipher.
+    pub tls: BTreeMap<tls::TlsSession, u64>,
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+/// A connection made by `Connector`, carrying its `ConnectionInfo` to the responses it produces.
+pub struct Connection {
+    stream: Stream,
+    /// Requests go to an HTTP proxy, which needs the full URL in the request line.
+    forwarded: bool,
+    /// Frees a slot of --max-connections-per-host when the connection closes.
+    _permit: Option<OwnedSemaphorePermit>,
+    info: Arc<ConnectionInfo>,
+}
+
+impl HyperConnection for Connection {
+    fn connected(&self) -> Connected {
+        let connected = Connected::new()
+            .proxy(self.forwarded)
+            .extra(self.info.clone());
+
+        match &self.stream {
+            Stream::Tls(tls) if tls.get_ref().1.alpn_protocol() == Some(b"h2") => {
+                connected.negotiated_h2()
+            }
+            _ => connected,
+        }
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_read(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write(cx, buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[std::io::IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match &self.stream {
+            Stream::Tcp(stream) => stream.is_write_vectored(),
+            Stream::Tls(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_flush(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Tls(stream) => Pin::new(stream.as_mut()).poll_shutdown(cx),
+            #[cfg(unix)]
+            Stream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Caps the connections open at once to each scheme, host and port.
+struct ConnectionLimits {
+    max: usize,
+    hosts: std::sync::Mutex<HashMap<String, Arc<Semaphore>>>,
+}
+
+impl ConnectionLimits {
+    /// Waits until fewer than `max` connections to `uri`'s origin are open.
+    async fn acquire(&self, uri: &Uri) -> OwnedSemaphorePermit {
+        let origin = format!(
+            "{}://{}",
+            uri.scheme_str().unwrap_or_default(),
+            uri.authority().map(|a| a.as_str()).unwrap_or_default()
+        );
+
+        let semaphore = self
+            .hosts
+            .lock()
+            .unwrap()
+            .entry(origin)
+            .or_insert_with(|| Arc::new(Semaphore::new(self.max)))
+            .clone();
+
+        // The semaphore is never closed.
+        semaphore.acquire_owned().await.unwrap()
+    }
+}
+
+/// Opens connections for the HTTP client, timing DNS resolution, TCP connect and the TLS handshake.
+#[derive(Clone)]
+pub struct Connector {
+    resolver: Arc<TokioAsyncResolver>,
+    tls: tokio_rustls::TlsConnector,
+    connect_timeout: Duration,
+    /// Fail TLS connections on which the server didn't pick HTTP/2 with ALPN.
+    require_h2: bool,
+    overrides: Arc<resolve::Overrides>,
+    proxies: Arc<proxy::Proxies>,
+    limits: Option<Arc<ConnectionLimits>>,
+    /// Every connection goes to this socket instead of the URL host, see --unix-socket.
+    #[cfg(unix)]
+    unix_socket: Option<Arc<PathBuf>>,
+    /// Start each new connection at the next resolved address instead of the first one. Pooled
+    /// connections keep their address, so requests only spread with --no-keepalive.
+    round_robin: bool,
+    /// Shared by the clones of the connector, so every connection moves on to the next address.
+    next_address: Arc<AtomicUsize>,
+    counts: Arc<std::sync::Mutex<ConnectionCounts>>,
+}
+
+impl Connector {
+    pub fn new(
+        resolver: TokioAsyncResolver,
+        tls: rustls::ClientConfig,
+        connect_timeout: Duration,
+    ) -> Self {
+        Connector {
+            resolver: Arc::new(resolver),
+            tls: tokio_rustls::TlsConnector::from(Arc::new(tls)),
+            connect_timeout,
+            require_h2: false,
+            overrides: Arc::new(resolve::Overrides::default()),
+            proxies: Arc::new(proxy::Proxies::default()),
+            limits: None,
+            #[cfg(unix)]
+            unix_socket: None,
+            round_robin: false,
+            next_address: Arc::new(AtomicUsize::new(0)),
+            counts: Default::default(),
+        }
+    }
+
+    /// Connections opened so far by this connector and its clones.
+    pub fn counts(&self) -> ConnectionCounts {
+        self.counts.lock().unwrap().clone()
+    }
+
+    fn count(&self, tls_session: Option<tls::TlsSession>) {
+        let mut counts = self.counts.lock().unwrap();
+        counts.opened += 1;
+
+        if let Some(session) = tls_session {
+            *counts.tls.entry(session).or_insert(0) += 1;
+        }
+    }
+
+    pub fn require_h2(mut self, required: bool) -> Self {
+        self.require_h2 = required;
+        self
+    }
+
+    /// Connect according to --resolve and --connect-to instead of the URL host and port.
+    pub fn overrides(mut self, overrides: resolve::Overrides) -> Self {
+        self.overrides = Arc::new(overrides);
+        self
+    }
+
+    pub fn round_robin(mut self, enabled: bool) -> Self {
+        self.round_robin = enabled;
+        self
+    }
+
+    pub fn proxies(mut self, proxies: Arc<proxy::Proxies>) -> Self {
+        self.proxies = proxies;
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn unix_socket(mut self, path: Option<PathBuf>) -> Self {
+        self.unix_socket = path.map(Arc::new);
+        self
+    }
+
+    /// New connections wait while `max` connections to the same origin are open.
+    pub fn max_connections_per_host(mut self, max: Option<usize>) -> Self {
+        self.limits = max.map(|max| {
+            Arc::new(ConnectionLimits {
+                max,
+                hosts: std::sync::Mutex::new(HashMap::new()),
+            })
+        });
+        self
+    }
+
+    /// The addresses to try for `route`, looked up with DNS unless --resolve or an IP address gave
+    /// them, and the time the lookup took.
+    async fn addresses(
+        &self,
+        route: &resolve::Route,
+    ) -> std::io::Result<(Vec<IpAddr>, Option<Duration>)> {
+        let started = Instant::now();
+
+        let (mut addresses, dns) = match route.addresses.clone() {
+            Some(addresses) => (addresses, None),
+            None => {
+                let lookup = self
+                    .resolver
+                    .lookup_ip(route.host.as_str())
+                    .await
+                    .map_err(std::io::Error::other)?;
+
+                (lookup.iter().collect::<Vec<_>>(), Some(started.elapsed()))
+            }
+        };
+
+        if self.round_robin && !addresses.is_empty() {
+            let next = self.next_address.fetch_add(1, Ordering::Relaxed);
+            let len = addresses.len();
+            addresses.rotate_left(next % len);
+        }
+
+        Ok((addresses, dns))
+    }
+
+    async fn connect(self, uri: Uri) -> std::io::Result<Connection> {
+        let https = match uri.scheme_str() {
+            Some("https") => true,
+            Some("http") => false,
+            _ => return Err(invalid_input(format!("unsupported URL scheme: {uri}"))),
+        };
+
+        #[cfg(unix)]
+        if let Some(path) = self.unix_socket.as_ref() {
+            if https {
+                return Err(invalid_input(format!(
+                    "HTTPS is not supported over unix sockets: {uri}"
+                )));
+            }
+
+            let connect_started = Instant::now();
+            let stream = UnixStream::connect(path.as_path())
+                .await
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{e}: {}", path.display())))?;
+
+            self.count(None);
+
+            return Ok(Connection {
+                stream: Stream::Unix(stream),
+                forwarded: false,
+                _permit: None,
+                info: Arc::new(ConnectionInfo {
+                    dns: None,
+                    connect: connect_started.elapsed(),
+                    tls: None,
+                    requests: AtomicU64::new(0),
+                }),
+            });
+        }
+
+        let host = uri
+            .host()
+            .ok_or_else(|| invalid_input(format!("URL without host: {uri}")))?
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .to_string();
+        let port = uri.port_u16().unwrap_or(if https { 443 } else { 80 });
+
+        // The Host header and TLS server name stay those of the URL whatever the route.
+        let route = self.overrides.route(&host, port);
+        let proxy = self.proxies.get(https, &host).cloned();
+
+        let (tcp, dns, connect, forwarded) = match proxy {
+            None => {
+                let (addresses, dns) = self.addresses(&route).await?;
+
+                let connect_started = Instant::now();
+                let tcp = connect_any(&addresses, route.port).await?;
+
+                (tcp, dns, connect_started.elapsed(), false)
+            }
+            Some(proxy) => {
+                let (addresses, mut dns) = self
+                    .addresses(&self.overrides.route(&proxy.host, proxy.port))
+                    .await?;
+
+                // With socks5:// the target is resolved here, the proxy only gets its address.
+                let target = match proxy.kind {
+                    proxy::ProxyKind::Socks5 => {
+                        let (targets, target_dns) = self.addresses(&route).await?;
+                        dns = match (dns, target_dns) {
+                            (None, None) => None,
+                            (dns, target_dns) => {
+                                Some(dns.unwrap_or_default() + target_dns.unwrap_or_default())
+                            }
+                        };
+
+                        targets.first().copied()
+                    }
+                    _ => None,
+                };
+
+                let connect_started = Instant::now();
+                let tcp = connect_any(&addresses, proxy.port).await?;
+
+                let tcp = match (proxy.kind, target) {
+                    // Plain HTTP requests are sent to the proxy as is, with the full URL.
+                    (proxy::ProxyKind::Http, _) if !https => tcp,
+                    (proxy::ProxyKind::Http, _) => {
+                        tunnel(tcp, &route.host, route.port, proxy.authorization()).await?
+                    }
+                    (_, Some(target)) => {
+                        socks5(tcp, SocketAddr::new(target, route.port), &proxy).await?
+                    }
+                    _ => socks5(tcp, (route.host.clone(), route.port), &proxy).await?,
+                };
+
+                (
+                    tcp,
+                    dns,
+                    connect_started.elapsed(),
+                    proxy.kind == proxy::ProxyKind::Http && !https,
+                )
+            }
+        };
+
+        let (stream, tls) = match https {
+            true => {
+                let tls_started = Instant::now();
+                let server_name = rustls::ServerName::try_from(host.as_str())
+                    .map_err(|e| invalid_input(format!("{e}: {host}")))?;
+                let stream = self.tls.connect(server_name, tcp).await?;
+
+                if self.require_h2 && stream.get_ref().1.alpn_protocol() != Some(b"h2") {
+                    return Err(std::io::Error::other(format!(
+                        "{host} did not negotiate HTTP/2"
+                    )));
+                }
+
+                self.count(Some(tls::TlsSession::from_connection(stream.get_ref().1)));
+
+                (Stream::Tls(Box::new(stream)), Some(tls_started.elapsed()))
+            }
+            false => {
+                self.count(None);
+
+                (Stream::Tcp(tcp), None)
+            }
+        };
+
+        Ok(Connection {
+            stream,
+            forwarded,
+            _permit: None,
+            info: Arc::new(ConnectionInfo {
+                dns,
+                connect,
+                tls,
+                requests: AtomicU64::new(0),
+            }),
+        })
+    }
+}
+
+impl tower_service::Service<Uri> for Connector {
+    type Response = Connection;
+    type Error = std::io::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Connection, std::io::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+        let connect_timeout = self.connect_timeout;
+
+        Box::pin(async move {
+            // Waiting for a free slot counts towards the connect timeout, so a host that is
+            // at its limit with busy connections fails like one that doesn't accept any.
+            let connect = async {
+                let permit = match connector.limits.as_ref() {
+                    Some(limits) => Some(limits.acquire(&uri).await),
+                    None => None,
+                };
+
+                connector.connect(uri).await.map(|mut connection| {
+                    connection._permit = permit;
+                    connection
+                })
+            };
+
+            match tokio::time::timeout(connect_timeout, connect).await {
+                Ok(connection) => connection,
+                Err(_) => Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "connect timed out",
+                )),
+            }
+        })
+    }
+}
+
+/// Tries every resolved address in order and returns the first successful connection.
+async fn connect_any(addresses: &[IpAddr], port: u16) -> std::io::Result<TcpStream> {
+    let mut last_error = None;
+
+    for ip in addresses {
+        match TcpStream::connect(SocketAddr::new(*ip, port)).await {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, "no addresses found for host")
+    }))
+}
+
+/// Opens a tunnel to `host:port` through an HTTP proxy with CONNECT.
+async fn tunnel(
+    mut tcp: TcpStream,
+    host: &str,
+    port: u16,
+    authorization: Option<hyper::header::HeaderValue>,
+) -> std::io::Result<TcpStream> {
+    let authority = match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    };
+
+    let mut request = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n");
+    if let Some(authorization) = authorization.as_ref().and_then(|a| a.to_str().ok()) {
+        request.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+    }
+    request.push_str("\r\n");
+    tcp.write_all(request.as_bytes()).await?;
+
+    // The server speaks first only after the TLS client hello, so nothing past the headers is read.
+    let mut response = vec![];
+    while !response.ends_with(b"\r\n\r\n") {
+        let mut buf = [0; 1];
+        if tcp.read(&mut buf).await? == 0 || response.len() > 64 * 1024 {
+            return Err(std::io::Error::other(format!(
+                "proxy closed the CONNECT tunnel to {authority}"
+            )));
+        }
+        response.push(buf[0]);
+    }
+
+    let response = String::from_utf8_lossy(&response);
+    let status_line = response.lines().next().unwrap_or_default();
+
+    match status_line.split_whitespace().nth(1) {
+        Some(status) if status.starts_with('2') => Ok(tcp),
+        _ => Err(std::io::Error::other(format!(
+            "proxy refused CONNECT to {authority}: {status_line}"
+        ))),
+    }
+}
+
+/// Asks a SOCKS5 proxy to connect to `target`, with the proxy credentials if any.
+async fn socks5(
+    tcp: TcpStream,
+    target: impl tokio_socks::IntoTargetAddr<'static>,
+    proxy: &proxy::Proxy,
+) -> std::io::Result<TcpStream> {
+    let stream = match proxy.username.as_deref() {
+        Some(username) => {
+            tokio_socks::tcp::Socks5Stream::connect_with_password_and_socket(
+                tcp,
+                target,
+                username,
+                proxy.password.as_deref().unwrap_or_default(),
+            )
+            .await
+        }
+        None => tokio_socks::tcp::Socks5Stream::connect_with_socket(tcp, target).await,
+    }
+    .map_err(|e| std::io::Error::other(format!("SOCKS5 proxy error: {e}")))?;
+
+    Ok(stream.into_inner())
+}
+
+fn invalid_input(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
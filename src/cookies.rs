@@ -0,0 +1,120 @@
+use super::*;
+use cookie_store::CookieStore;
+use hyper::header::{self, HeaderMap, HeaderValue};
+use std::sync::Mutex;
+
+/// Cookies set by the responses a virtual user received, sent back on its next requests.
+#[derive(Debug, Default)]
+pub struct Jar {
+    store: Mutex<CookieStore>,
+}
+
+impl Jar {
+    /// Adds the cookies stored for `url` to the Cookie header, after any cookies given with --header.
+    pub fn add_cookie_header(&self, url: &Url, headers: &mut HeaderMap) {
+        let store = self.store.lock().unwrap();
+
+        let cookies = headers
+            .get(header::COOKIE)
+            .and_then(|cookie| cookie.to_str().ok())
+            .map(|cookie| cookie.to_string())
+            .into_iter()
+            .chain(
+                store
+                    .get_request_values(url)
+                    .map(|(name, value)| format!("{name}={value}")),
+            )
+            .collect::<Vec<_>>();
+
+        if cookies.is_empty() {
+            return;
+        }
+
+        if let Ok(value) = HeaderValue::from_str(&cookies.join("; ")) {
+            headers.insert(header::COOKIE, value);
+        }
+    }
+
+    /// Stores the Set-Cookie headers of the response to a request for `url`.
+    pub fn store(&self, url: &Url, headers: &HeaderMap) {
+        let mut store = self.store.lock().unwrap();
+
+        for cookie in headers.get_all(header::SET_COOKIE) {
+            if let Ok(cookie) = cookie.to_str() {
+                // Invalid cookies, or cookies for another domain, are dropped like browsers do.
+                let _ = store.parse(cookie, url);
+            }
+        }
+    }
+}
+
+/// The cookie jars of a run: none, one shared by every virtual user, or one per virtual user.
+#[derive(Debug, Default)]
+pub struct Jars(Vec<Jar>);
+
+impl Jars {
+    /// `users` is the number of worker slots of the run.
+    pub fn new(mode: Option<config::CookieJar>, users: usize) -> Self {
+        let count = match mode {
+            None => 0,
+            Some(config::CookieJar::Global) => 1,
+            Some(config::CookieJar::User) => users.max(1),
+        };
+
+        Jars((0..count).map(|_| Jar::default()).collect())
+    }
+
+    /// The jar of the virtual user in worker slot `worker_id`.
+    pub fn get(&self, worker_id: usize) -> Option<&Jar> {
+        match self.0.len() {
+            0 => None,
+            len => self.0.get(worker_id % len),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Jars;
+    use crate::config::CookieJar;
+    use hyper::header::{self, HeaderMap, HeaderValue};
+    use url::Url;
+
+    #[test]
+    fn keeps_cookies_per_user() {
+        let url = Url::parse("https://shop.example.com/login").unwrap();
+        let mut response = HeaderMap::new();
+        response.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("session=abc; Path=/"),
+        );
+        response.append(
+            header::SET_COOKIE,
+            HeaderValue::from_static("other=1; Domain=elsewhere.com"),
+        );
+
+        let jars = Jars::new(Some(CookieJar::User), 2);
+        jars.get(0).unwrap().store(&url, &response);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_static("theme=dark"));
+        let cart = Url::parse("https://shop.example.com/cart").unwrap();
+        jars.get(0).unwrap().add_cookie_header(&cart, &mut headers);
+        assert_eq!(headers[header::COOKIE], "theme=dark; session=abc");
+
+        // Other virtual users have their own session.
+        let mut headers = HeaderMap::new();
+        jars.get(1).unwrap().add_cookie_header(&cart, &mut headers);
+        assert!(headers.is_empty());
+
+        let global = Jars::new(Some(CookieJar::Global), 2);
+        global.get(0).unwrap().store(&url, &response);
+        global
+            .get(1)
+            .unwrap()
+            .add_cookie_header(&cart, &mut headers);
+        assert_eq!(headers[header::COOKIE], "session=abc");
+
+        assert!(Jars::new(None, 2).get(0).is_none());
+    }
+}
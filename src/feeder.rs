@@ -0,0 +1,147 @@
+use super::*;
+use rand::seq::SliceRandom;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DataStrategy {
+    /// Rows in file order, starting over at the end.
+    Sequential,
+    Random,
+    /// Every row is used once, the run stops when all of them were sent.
+    Unique,
+}
+
+/// Rows of a --data-file, every row holding one value per column.
+#[derive(Debug, Clone)]
+pub struct DataSet {
+    pub columns: Vec<String>,
+    pub rows: Vec<Arc<Vec<String>>>,
+}
+
+impl DataSet {
+    /// Loads a CSV file with a header row, or a JSON array of objects when the path ends in `.json`.
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let data = match path.to_lowercase().ends_with(".json") {
+            true => DataSet::from_json(&std::fs::read_to_string(path)?)?,
+            false => DataSet::from_csv(std::fs::File::open(path)?)?,
+        };
+
+        if data.rows.is_empty() {
+            anyhow::bail!("no rows found");
+        }
+
+        Ok(data)
+    }
+
+    fn from_csv(input: impl std::io::Read) -> anyhow::Result<Self> {
+        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(input);
+
+        let columns = reader.headers()?.iter().map(|c| c.to_string()).collect();
+        let rows = reader
+            .records()
+            .map(|record| Ok(Arc::new(record?.iter().map(|v| v.to_string()).collect())))
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(DataSet { columns, rows })
+    }
+
+    /// Columns are the keys of all objects, in order of first appearance. Strings are used as is,
+    /// other values as JSON and missing keys as empty strings.
+    fn from_json(input: &str) -> anyhow::Result<Self> {
+        let objects: Vec<serde_json::Map<String, serde_json::Value>> = serde_json::from_str(input)?;
+
+        let mut columns: Vec<String> = vec![];
+        for key in objects.iter().flat_map(|object| object.keys()) {
+            if !columns.contains(key) {
+                columns.push(key.clone());
+            }
+        }
+
+        let rows = objects
+            .iter()
+            .map(|object| {
+                Arc::new(
+                    columns
+                        .iter()
+                        .map(|column| match object.get(column) {
+                            Some(serde_json::Value::String(value)) => value.clone(),
+                            Some(serde_json::Value::Null) | None => String::new(),
+                            Some(value) => value.to_string(),
+                        })
+                        .collect(),
+                )
+            })
+            .collect();
+
+        Ok(DataSet { columns, rows })
+    }
+}
+
+/// Hands out rows to requests, safe to share between tasks.
+pub struct Feeder {
+    data: DataSet,
+    strategy: DataStrategy,
+    next: AtomicUsize,
+}
+
+impl Feeder {
+    pub fn new(data: DataSet, strategy: DataStrategy) -> Self {
+        Feeder {
+            data,
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// The row for the next request, `None` once every row was used with `DataStrategy::Unique`.
+    pub fn next(&self) -> Option<Arc<Vec<String>>> {
+        let rows = &self.data.rows;
+
+        match self.strategy {
+            DataStrategy::Sequential => {
+                Some(rows[self.next.fetch_add(1, Ordering::Relaxed) % rows.len()].clone())
+            }
+            DataStrategy::Random => rows.choose(&mut rand::thread_rng()).cloned(),
+            DataStrategy::Unique => rows.get(self.next.fetch_add(1, Ordering::Relaxed)).cloned(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DataSet, DataStrategy, Feeder};
+
+    #[test]
+    fn loads_csv_and_json() {
+        let csv = DataSet::from_csv("user_id,token\n1,\"a,b\"\n2,c\n".as_bytes()).unwrap();
+        assert_eq!(csv.columns, vec!["user_id", "token"]);
+        assert_eq!(*csv.rows[0], vec!["1", "a,b"]);
+        assert_eq!(csv.rows.len(), 2);
+
+        let json = DataSet::from_json(r#"[{"id": 1, "name": "a"}, {"name": "b", "extra": null}]"#)
+            .unwrap();
+        assert_eq!(json.columns, vec!["id", "name", "extra"]);
+        assert_eq!(*json.rows[0], vec!["1", "a", ""]);
+        assert_eq!(*json.rows[1], vec!["", "b", ""]);
+    }
+
+    #[test]
+    fn feeds_rows_by_strategy() {
+        let data = DataSet::from_csv("id\n1\n2\n".as_bytes()).unwrap();
+
+        let sequential = Feeder::new(data.clone(), DataStrategy::Sequential);
+        let ids: Vec<_> = (0..3)
+            .map(|_| sequential.next().unwrap()[0].clone())
+            .collect();
+        assert_eq!(ids, vec!["1", "2", "1"]);
+
+        let unique = Feeder::new(data.clone(), DataStrategy::Unique);
+        assert!(unique.next().is_some());
+        assert!(unique.next().is_some());
+        assert!(unique.next().is_none());
+
+        let random = Feeder::new(data, DataStrategy::Random);
+        assert!((0..10).all(|_| random.next().is_some()));
+    }
+}
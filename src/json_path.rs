@@ -0,0 +1,95 @@
+use serde_json::Value;
+
+#[derive(Debug, Clone, PartialEq)]
+enum JsonPathSegment {
+    Key(String),
+    /// Negative indexes count from the end of the array.
+    Index(i64),
+}
+
+/// A JSONPath subset: `$` followed by `.key`, `['key']` and `[index]` segments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath(Vec<JsonPathSegment>);
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let invalid = || format!("Invalid JSONPath: {path}");
+
+        let mut rest = path.trim().strip_prefix('$').ok_or_else(invalid)?;
+        let mut segments = vec![];
+
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                if end == 0 {
+                    return Err(invalid());
+                }
+
+                segments.push(JsonPathSegment::Key(after[..end].to_string()));
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(invalid)?;
+                let inner = after[..end].trim();
+                let quoted = inner
+                    .strip_prefix('\'')
+                    .and_then(|key| key.strip_suffix('\''))
+                    .or_else(|| {
+                        inner
+                            .strip_prefix('"')
+                            .and_then(|key| key.strip_suffix('"'))
+                    });
+
+                segments.push(match quoted {
+                    Some(key) => JsonPathSegment::Key(key.to_string()),
+                    None => JsonPathSegment::Index(inner.parse().map_err(|_| invalid())?),
+                });
+                rest = &after[end + 1..];
+            } else {
+                return Err(invalid());
+            }
+        }
+
+        Ok(JsonPath(segments))
+    }
+
+    pub fn select<'a>(&self, value: &'a Value) -> Option<&'a Value> {
+        self.0
+            .iter()
+            .try_fold(value, |value, segment| match segment {
+                JsonPathSegment::Key(key) => value.get(key),
+                JsonPathSegment::Index(index) => {
+                    let array = value.as_array()?;
+                    let index = match *index < 0 {
+                        true => array.len() as i64 + index,
+                        false => *index,
+                    };
+
+                    array.get(usize::try_from(index).ok()?)
+                }
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonPath;
+
+    #[test]
+    fn json_path() {
+        let json = serde_json::json!({"data": {"items": [{"id": 1}, {"id": 2, "a b": "x"}]}});
+
+        let select = |path| JsonPath::parse(path).unwrap().select(&json).cloned();
+        assert_eq!(select("$.data.items[0].id"), Some(serde_json::json!(1)));
+        assert_eq!(
+            select("$.data.items[-1]['a b']"),
+            Some(serde_json::json!("x"))
+        );
+        assert_eq!(select("$['data'].items[5]"), None);
+        assert_eq!(select("$.missing"), None);
+        assert_eq!(select("$"), Some(json.clone()));
+
+        assert!(JsonPath::parse("data.items").is_err());
+        assert!(JsonPath::parse("$..id").is_err());
+        assert!(JsonPath::parse("$.items[x]").is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use super::*;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Bumped whenever a field is renamed or removed, adding fields keeps the version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+pub struct JsonReport {
+    pub schema_version: u32,
+    pub tool: Tool,
+    pub config: RunConfig,
+    pub timing: Timing,
+    pub totals: Totals,
+    pub status_codes: BTreeMap<String, u64>,
+    /// Responses by HTTP version, ex: `HTTP/1.1` or `HTTP/2`.
+    pub protocols: BTreeMap<String, u64>,
+    /// TLS handshakes by negotiated version and cipher.
+    pub tls: Vec<TlsReport>,
+    pub errors: BTreeMap<String, u64>,
+    /// Failures of every configured check, by check name.
+    pub checks: BTreeMap<String, u64>,
+    pub latency: Latency,
+    /// Only present in --rate mode.
+    pub dispatch: Option<Dispatch>,
+    /// Latency of each request phase: dns, connect, tls, ttfb and download.
+    pub phases: BTreeMap<String, PhaseReport>,
+    pub stages: Vec<StageReport>,
+    /// Per-URL breakdown in --group-sort order, empty unless grouping is enabled.
+    pub groups: Vec<GroupReport>,
+    /// Every --threshold with its outcome, in the order given.
+    pub thresholds: Vec<ThresholdReport>,
+}
+
+#[derive(Serialize)]
+pub struct Tool {
+    pub name: &'static str,
+    pub version: &'static str,
+}
+
+#[derive(Serialize)]
+pub struct RunConfig {
+    pub mode: String,
+    pub method: String,
+    pub url: Option<String>,
+    pub urls: Option<usize>,
+    pub concurrent: u16,
+    pub rate: Option<f64>,
+    pub requests: Option<u64>,
+    pub duration_secs: Option<u64>,
+    pub timeout_ms: u64,
+    pub timeout_connect_ms: u64,
+    pub follow_redirects: bool,
+    pub disable_compression: bool,
+    pub random_arguments: bool,
+}
+
+#[derive(Serialize)]
+pub struct Timing {
+    pub started_at_ms: u64,
+    pub finished_at_ms: u64,
+    pub elapsed_secs: f64,
+}
+
+#[derive(Serialize)]
+pub struct Totals {
+    pub requests: u64,
+    pub responses: u64,
+    pub errors: u64,
+    /// Responses that failed at least one check.
+    pub failed_checks: u64,
+    pub body_bytes: u64,
+    pub requests_per_second: f64,
+    pub connections_opened: u64,
+    pub connections_reused: u64,
+    /// Requests sent once more after a 401, see `stats::Summary::retried`.
+    pub auth_retries: u64,
+}
+
+#[derive(Serialize)]
+pub struct Latency {
+    pub raw: Option<LatencyReport>,
+    pub corrected: Option<LatencyReport>,
+    /// Send interval per worker assumed by the correction, the average concurrency divided by the
+    /// throughput. Absent in --rate mode where latency is measured from the scheduled send time.
+    pub correction_interval_us: Option<u64>,
+}
+
+/// Latency distribution, all values in microseconds.
+#[derive(Serialize)]
+pub struct LatencyReport {
+    pub min_us: u64,
+    pub max_us: u64,
+    pub mean_us: f64,
+    pub stddev_us: f64,
+    pub percentiles_us: BTreeMap<String, u64>,
+}
+
+#[derive(Serialize)]
+pub struct Dispatch {
+    pub dropped: u64,
+    pub late: u64,
+}
+
+#[derive(Serialize)]
+pub struct StageReport {
+    pub duration_secs: u64,
+    pub target: f64,
+    pub responses: u64,
+    pub errors: u64,
+    pub requests_per_second: f64,
+    pub latency: Option<LatencyReport>,
+    pub corrected_latency: Option<LatencyReport>,
+}
+
+/// DNS, connect and TLS are only sampled for requests that opened a new connection.
+#[derive(Serialize)]
+pub struct PhaseReport {
+    pub samples: u64,
+    pub latency: Option<LatencyReport>,
+}
+
+#[derive(Serialize)]
+pub struct GroupReport {
+    pub key: String,
+    pub requests: u64,
+    pub responses: u64,
+    pub errors: u64,
+    pub error_rate: f64,
+    pub failed_checks: u64,
+    pub status_codes: BTreeMap<String, u64>,
+    pub latency: Option<LatencyReport>,
+    pub corrected_latency: Option<LatencyReport>,
+}
+
+#[derive(Serialize)]
+pub struct TlsReport {
+    pub version: String,
+    pub cipher: String,
+    pub handshakes: u64,
+}
+
+#[derive(Serialize)]
+pub struct ThresholdReport {
+    pub expression: String,
+    /// Formatted as in the summary, `None` when nothing could be measured.
+    pub actual: Option<String>,
+    pub passed: bool,
+}
+
+impl From<report::LatencyStats> for LatencyReport {
+    fn from(stats: report::LatencyStats) -> Self {
+        LatencyReport {
+            min_us: stats.min,
+            max_us: stats.max,
+            mean_us: stats.mean,
+            stddev_us: stats.stddev,
+            percentiles_us: report::PERCENTILES
+                .iter()
+                .zip(stats.percentiles)
+                .map(|((_, quantile), value)| (percentile_key(*quantile), value))
+                .collect(),
+        }
+    }
+}
+
+impl JsonReport {
+    pub fn new(config: &config::Config, run: &process::RunResults, elapsed: Duration) -> Self {
+        let summary = &run.stats.total;
+        let finished_at = SystemTime::now();
+        let started_at = finished_at.checked_sub(elapsed).unwrap_or(UNIX_EPOCH);
+        // Groups share the send interval of the whole run, see `report::print`.
+        let correction = report::Correction::for_run(config, run, elapsed);
+        let stage_corrections = report::Correction::for_stages(config, run, elapsed);
+
+        JsonReport {
+            schema_version: SCHEMA_VERSION,
+            tool: Tool {
+                name: APP_NAME,
+                version: VERSION,
+            },
+            config: RunConfig {
+                mode: format!("{:?}", config.mode).to_lowercase(),
+                method: config.method.to_string(),
+                url: config.url.as_ref().map(|url| url.to_string()),
+                urls: config.urls.as_ref().map(|urls| urls.len()),
+                concurrent: config.concurrent,
+                rate: config.rate,
+                requests: config.requests,
+                duration_secs: config.duration.map(|duration| duration.as_secs()),
+                timeout_ms: config.timeout.as_millis() as u64,
+                timeout_connect_ms: config.timeout_connect.as_millis() as u64,
+                follow_redirects: config.follow_redirects,
+                disable_compression: config.disable_compression,
+                random_arguments: config.random_arguments,
+            },
+            timing: Timing {
+                started_at_ms: unix_millis(started_at),
+                finished_at_ms: unix_millis(finished_at),
+                elapsed_secs: elapsed.as_secs_f64(),
+            },
+            totals: Totals {
+                requests: summary.total(),
+                responses: summary.responses,
+                errors: summary.error_count(),
+                failed_checks: summary.failed_checks,
+                body_bytes: summary.body_length,
+                requests_per_second: summary.responses as f64 / elapsed.as_secs_f64(),
+                connections_opened: run.connections.opened,
+                connections_reused: summary.connections_reused,
+                auth_retries: summary.retried,
+            },
+            status_codes: status_codes(summary),
+            protocols: summary
+                .protocols
+                .iter()
+                .map(|(protocol, count)| (protocol.to_string(), *count))
+                .collect(),
+            tls: run
+                .connections
+                .tls
+                .iter()
+                .map(|(session, handshakes)| TlsReport {
+                    version: session.version.clone(),
+                    cipher: session.cipher.clone(),
+                    handshakes: *handshakes,
+                })
+                .collect(),
+            errors: summary
+                .errors
+                .iter()
+                .map(|(kind, count)| (kind.as_str().to_string(), *count))
+                .collect(),
+            checks: report::configured_checks(config)
+                .into_iter()
+                .map(|check| {
+                    (
+                        check.to_string(),
+                        summary.checks.get(check).copied().unwrap_or(0),
+                    )
+                })
+                .collect(),
+            latency: Latency {
+                raw: report::LatencyStats::from_histogram(&summary.latency).map(Into::into),
+                corrected: report::LatencyStats::from_histogram(&correction.apply(summary))
+                    .map(Into::into),
+                correction_interval_us: match correction {
+                    report::Correction::Interval(interval) => Some(interval),
+                    report::Correction::Scheduled => None,
+                },
+            },
+            dispatch: config.rate.map(|_| Dispatch {
+                dropped: run.dispatch.dropped,
+                late: run.dispatch.late,
+            }),
+            phases: run
+                .stats
+                .phases
+                .iter()
+                .iter()
+                .map(|(name, histogram)| {
+                    (
+                        name.to_string(),
+                        PhaseReport {
+                            samples: histogram.len(),
+                            latency: report::LatencyStats::from_histogram(histogram)
+                                .map(Into::into),
+                        },
+                    )
+                })
+                .collect(),
+            stages: config
+                .stages
+                .iter()
+                .flatten()
+                .enumerate()
+                .map(|(index, stage)| {
+                    let summary = run.stats.stages.get(index).cloned().unwrap_or_default();
+
+                    StageReport {
+                        duration_secs: stage.duration.as_secs(),
+                        target: stage.target,
+                        responses: summary.responses,
+                        errors: summary.error_count(),
+                        requests_per_second: summary.responses as f64
+                            / stage.duration.as_secs_f64(),
+                        latency: report::LatencyStats::from_histogram(&summary.latency)
+                            .map(Into::into),
+                        corrected_latency: report::LatencyStats::from_histogram(
+                            &stage_corrections[index].apply(&summary),
+                        )
+                        .map(Into::into),
+                    }
+                })
+                .collect(),
+            groups: match config.grouping.as_ref() {
+                Some(grouping) => report::sorted_groups(grouping, &run.stats)
+                    .into_iter()
+                    .map(|(key, summary)| GroupReport {
+                        key: key.clone(),
+                        requests: summary.total(),
+                        responses: summary.responses,
+                        errors: summary.error_count(),
+                        error_rate: report::error_rate(summary),
+                        failed_checks: summary.failed_checks,
+                        status_codes: status_codes(summary),
+                        latency: report::LatencyStats::from_histogram(&summary.latency)
+                            .map(Into::into),
+                        corrected_latency: report::LatencyStats::from_histogram(
+                            &correction.apply(summary),
+                        )
+                        .map(Into::into),
+                    })
+                    .collect(),
+                None => vec![],
+            },
+            thresholds: thresholds::evaluate(config, run, elapsed)
+                .into_iter()
+                .map(|evaluation| ThresholdReport {
+                    expression: evaluation.threshold.expression.clone(),
+                    actual: evaluation.actual,
+                    passed: evaluation.passed,
+                })
+                .collect(),
+        }
+    }
+}
+
+pub fn write(
+    path: &str,
+    config: &config::Config,
+    run: &process::RunResults,
+    elapsed: Duration,
+) -> anyhow::Result<()> {
+    let report = JsonReport::new(config, run, elapsed);
+    let mut writer = std::io::BufWriter::new(std::fs::File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, &report)?;
+    // Dropping the writer would ignore errors of the last write, ex: a full disk.
+    writer.flush()?;
+
+    Ok(())
+}
+
+fn status_codes(summary: &stats::Summary) -> BTreeMap<String, u64> {
+    summary
+        .status_codes
+        .iter()
+        .map(|(status, count)| (status.to_string(), *count))
+        .collect()
+}
+
+/// Stable key for a quantile: 0.5 -> `p50`, 0.999 -> `p99.9`.
+fn percentile_key(quantile: f64) -> String {
+    format!("p{}", (quantile * 1000.0).round() / 10.0)
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn percentile_keys() {
+        let keys: Vec<_> = crate::report::PERCENTILES
+            .iter()
+            .map(|(_, quantile)| super::percentile_key(*quantile))
+            .collect();
+
+        assert_eq!(keys, vec!["p50", "p75", "p90", "p95", "p99", "p99.9"]);
+    }
+}
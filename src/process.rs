@@ -1,9 +1,8 @@
 use super::*;
-use rand::prelude::*;
 use select::document::Document;
 use select::predicate::Name;
 use std::sync::mpsc::{channel, Sender};
-use tokio::sync::Semaphore;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 
 #[derive(Debug, Clone)]
 pub struct UrlItem {
@@ -14,71 +13,222 @@ pub struct UrlItem {
 #[derive(Debug, Clone)]
 pub enum Action {
     ProcessURL(UrlItem),
+    /// Runs the scenario steps once.
+    RunScenario,
     Ping,
 }
 
 #[derive(Debug)]
 pub struct HttpResponse {
-    pub status: reqwest::StatusCode,
+    pub status: hyper::StatusCode,
     pub duration: Duration,
+    /// Latency measured from the scheduled send time in --rate mode, so time spent
+    /// waiting to be dispatched is not hidden from the report.
+    pub scheduled_duration: Option<Duration>,
     pub length: usize,
+    pub timings: client::Timings,
+    pub version: hyper::Version,
+    pub connections_reused: u32,
+    /// A 401 response was answered with new credentials and the request sent once more.
+    pub retried: bool,
+    /// Names of the checks the response failed.
+    pub failed_checks: Vec<String>,
 }
 
 #[derive(Debug)]
 pub enum HttpResult {
     Response(HttpResponse),
-    Error(reqwest::Error),
+    Error(client::Error),
 }
 
-type HttpResultsHolder = Arc<Mutex<Vec<HttpResult>>>;
+/// How far behind its scheduled time a request may leave before it is counted as late in --rate mode.
+const LATE_DISPATCH_THRESHOLD: Duration = Duration::from_millis(5);
+
+/// Dispatch accounting for --rate mode, where requests follow a fixed timeline.
+#[derive(Debug, Default)]
+pub struct DispatchStats {
+    /// Scheduled requests that were skipped because every in-flight slot was busy.
+    pub dropped: u64,
+    /// Requests that left more than `LATE_DISPATCH_THRESHOLD` after their scheduled time.
+    pub late: u64,
+}
+
+#[derive(Debug)]
+pub struct RunResults {
+    pub stats: stats::RunStats,
+    pub dispatch: DispatchStats,
+    pub connections: connector::ConnectionCounts,
+}
+
+/// A concurrency slot held for the lifetime of a request. The id is stable per slot, so it
+/// identifies a virtual user across the requests it sends.
+pub struct WorkerSlot {
+    pub id: usize,
+    _permit: OwnedSemaphorePermit,
+    free_ids: Arc<Mutex<Vec<usize>>>,
+}
+
+impl WorkerSlot {
+    fn new(permit: OwnedSemaphorePermit, free_ids: &Arc<Mutex<Vec<usize>>>) -> Self {
+        WorkerSlot {
+            // There are never more permits than ids, so a held permit always has an id available.
+            id: free_ids.lock().unwrap().pop().unwrap(),
+            _permit: permit,
+            free_ids: free_ids.clone(),
+        }
+    }
+}
+
+impl Drop for WorkerSlot {
+    fn drop(&mut self) {
+        self.free_ids.lock().unwrap().push(self.id);
+    }
+}
+
+/// Per-run state shared by every spawned request.
+pub struct ExecuteContext {
+    pub tx: Sender<Action>,
+    pub http_client: client::HttpClient,
+    pub stats: stats::Recorder,
+    pub verbose: bool,
+    pub headers: hyper::header::HeaderMap,
+    pub mode: config::Mode,
+    pub method: hyper::Method,
+    pub allowed_domains: config::AllowedDomains,
+    pub basic_auth: Option<config::BasicAuth>,
+    pub bearer: Option<auth::Bearer>,
+    pub signers: Vec<Arc<dyn signers::Signer>>,
+    /// Indexed by worker slot, empty unless --cookie-jar is set.
+    pub cookies: cookies::Jars,
+    pub templates: Option<config::RequestTemplates>,
+    /// Feeds %SEQ%, incremented once per request.
+    pub sequence: std::sync::atomic::AtomicU64,
+    pub body: Option<body::RequestBody>,
+    /// `body` encoded once up front, used as is unless it contains templates.
+    pub encoded_body: hyper::body::Bytes,
+    pub request_log: Option<request_log::RequestLog>,
+    pub grouping: Option<config::Grouping>,
+    pub scenario: Option<scenario::Scenario>,
+    pub checks: Vec<checks::Check>,
+}
 
 #[tokio::main]
-pub async fn run(config: config::Config, requested_stop: Arc<AtomicBool>) -> HttpResultsHolder {
+pub async fn run(config: config::Config, requested_stop: Arc<AtomicBool>) -> RunResults {
     let allowed_domains = config.allowed_domains.clone();
-    let method = config.method.clone();
-    let verbose = config.verbose;
-    let headers = config.headers.clone();
     let prevent_duplicate_requests = config.prevent_duplicate_requests;
     let requests = config.requests;
     let duration = config.duration;
     let timeout = config.timeout;
     let concurrent = config.concurrent;
-    let basic_auth = config.basic_auth.clone();
-
-    let random_argument_regex = regex::Regex::new("%(?i)RAND\\((\\d{0,}),(\\d{0,})\\)%").unwrap();
+    let rate_mode = config.rate.is_some();
+    let profile = profile::LoadProfile::from_config(&config);
 
-    let random_arguments: Option<regex::Regex> = match config.random_arguments {
-        true => Some(random_argument_regex),
-        false => None,
+    // One id per slot the run can ever have in flight, handed out along with the permits.
+    let max_workers = match rate_mode {
+        true => concurrent as usize,
+        false => profile.max_value().ceil() as usize,
     };
 
     let ad = allowed_domains.clone();
-    let http_client = reqwest::Client::builder()
-        .redirect(match config.follow_redirects {
-            true => reqwest::redirect::Policy::custom(move |attempt| {
-                if attempt.previous().len() > 5 {
-                    attempt.error("too many redirects")
-                } else if is_allowed_host(&attempt.url(), &ad) {
-                    attempt.stop()
+    let builder = client::HttpClient::builder()
+        .redirect(match (config.follow_redirects, config.mode) {
+            // Scenario steps follow redirects to any host, their URLs aren't crawled.
+            (true, config::Mode::Scenario) => Some(Arc::new(|_: &Url, previous: &[Url]| {
+                match previous.len() > 5 {
+                    true => client::RedirectAction::Error,
+                    false => client::RedirectAction::Follow,
+                }
+            })),
+            (true, _) => Some(Arc::new(move |url: &Url, previous: &[Url]| {
+                if previous.len() > 5 {
+                    client::RedirectAction::Error
+                } else if is_allowed_host(url, &ad) {
+                    client::RedirectAction::Stop
                 } else {
-                    attempt.follow()
+                    client::RedirectAction::Follow
                 }
-            }),
-            false => reqwest::redirect::Policy::none(),
+            })),
+            (false, _) => None,
         })
-        .user_agent(format!("{}/{}", APP_NAME, VERSION))
+        .user_agent(&format!("{}/{}", APP_NAME, VERSION))
+        .http_version(config.http_version)
+        .tls(config.tls.clone())
+        .overrides(config.overrides.clone())
+        .round_robin(config.round_robin_ips)
+        .proxies(config.proxies.clone())
+        .keepalive(config.keepalive)
+        .max_connections_per_host(config.max_connections_per_host)
+        .pool_idle_timeout(config.pool_idle_timeout)
         .connect_timeout(config.timeout_connect)
         .timeout(timeout)
-        .gzip(!config.disable_compression)
-        .deflate(!config.disable_compression)
-        .use_rustls_tls()
-        .build()
-        .unwrap();
+        .compression(!config.disable_compression);
+    #[cfg(unix)]
+    let builder = builder.unix_socket(config.unix_socket.clone());
+    let http_client = builder.build().unwrap();
+
+    let bearer = match config.bearer.as_ref() {
+        Some(bearer) => match auth::Bearer::new(bearer, &http_client).await {
+            Ok(bearer) => Some(bearer),
+            Err(e) => {
+                error!("{}", e.red());
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let request_log = match config.log_requests.as_ref() {
+        Some(path) => match request_log::RequestLog::create(path, config.log_format) {
+            Ok(log) => Some(log),
+            Err(e) => {
+                error!("{} : {}", e.to_string().red(), path.magenta());
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let feeder = config
+        .data
+        .clone()
+        .map(|data| feeder::Feeder::new(data, config.data_strategy));
 
     let (tx, rx) = channel();
 
     let etx = tx.clone();
-    let mode = config.mode.clone();
+    let mode = config.mode;
+
+    let context = Arc::new(ExecuteContext {
+        tx: tx.clone(),
+        http_client,
+        stats: stats::Recorder::new(
+            std::thread::available_parallelism()
+                .map(|threads| threads.get())
+                .unwrap_or(1),
+        ),
+        verbose: config.verbose,
+        headers: config.headers.clone(),
+        mode,
+        method: config.method.clone(),
+        allowed_domains,
+        basic_auth: config.basic_auth.clone(),
+        bearer,
+        signers: config.signers.clone(),
+        cookies: cookies::Jars::new(config.cookie_jar, max_workers),
+        templates: config.templates.clone(),
+        sequence: std::sync::atomic::AtomicU64::new(0),
+        encoded_body: match config.body.as_ref() {
+            Some(body) => body
+                .encode(|template| template.render(&Default::default()))
+                .into(),
+            None => Default::default(),
+        },
+        body: config.body.clone(),
+        request_log,
+        grouping: config.grouping.clone(),
+        scenario: config.scenario.clone(),
+        checks: config.checks.clone(),
+    });
 
     std::thread::spawn(move || {
         let config = config.clone();
@@ -105,6 +255,8 @@ pub async fn run(config: config::Config, requested_stop: Arc<AtomicBool>) -> Htt
                 }
             }
 
+            config::Mode::Scenario => while etx.send(Action::RunScenario).is_ok() {},
+
             config::Mode::File => {
                 let mut i = 0;
                 loop {
@@ -134,8 +286,9 @@ pub async fn run(config: config::Config, requested_stop: Arc<AtomicBool>) -> Htt
     });
 
     let semaphore = Arc::new(Semaphore::new(concurrent as usize));
+    let mut concurrency_limit = concurrent as usize;
 
-    let results: HttpResultsHolder = Arc::new(Mutex::new(Vec::new()));
+    let worker_ids = Arc::new(Mutex::new((0..max_workers).rev().collect::<Vec<_>>()));
 
     let mut total_processed = 0;
     let mut should_process_work = true;
@@ -143,6 +296,8 @@ pub async fn run(config: config::Config, requested_stop: Arc<AtomicBool>) -> Htt
     let mut processed: HashSet<Url> = HashSet::new();
     let started = Instant::now();
     let mut last_send_progress = Instant::now();
+    let mut scheduled = 0;
+    let mut dispatch = DispatchStats::default();
 
     loop {
         if requests.is_some() && requests.unwrap() <= total_processed {
@@ -150,32 +305,67 @@ pub async fn run(config: config::Config, requested_stop: Arc<AtomicBool>) -> Htt
         }
 
         if !should_process_work {
-            if (total_processed != 0 && semaphore.available_permits() == concurrent as usize)
+            if (total_processed != 0 && semaphore.available_permits() == concurrency_limit)
                 || (requested_stop_at.is_some() && requested_stop_at.unwrap().elapsed() > timeout)
             {
                 break;
             } else {
                 continue;
             }
-        } else {
-            if requested_stop.load(std::sync::atomic::Ordering::Relaxed) {
+        } else if requested_stop.load(std::sync::atomic::Ordering::Relaxed) {
+            should_process_work = false;
+            requested_stop_at = Some(Instant::now());
+        } else if let Some(val) = duration {
+            if started.elapsed() >= val {
                 should_process_work = false;
-                requested_stop_at = Some(Instant::now());
-            } else {
-                if let Some(val) = duration {
-                    if started.elapsed() >= val {
-                        should_process_work = false;
-                    }
-                }
             }
         }
 
-        let permit = semaphore.clone().acquire_owned().await;
-        if permit.is_err() {
-            continue;
-        }
+        let (permit, scheduled_at) = match rate_mode {
+            true => {
+                let scheduled_at = match profile.dispatch_offset(scheduled) {
+                    Some(offset) => started + offset,
+                    None => {
+                        // The rate ramped down to zero, nothing is due until the run ends.
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        continue;
+                    }
+                };
+
+                // Wake up regularly on slow schedules so stop requests are not delayed.
+                if scheduled_at > Instant::now() + Duration::from_millis(100) {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
 
-        let permit = permit.unwrap();
+                tokio::time::sleep_until(scheduled_at.into()).await;
+
+                // In rate mode the schedule never waits for a free slot: a busy slot means a dropped request.
+                match semaphore.clone().try_acquire_owned() {
+                    Ok(permit) => (permit, Some(scheduled_at)),
+                    Err(_) => {
+                        dispatch.dropped += 1;
+                        scheduled += 1;
+                        continue;
+                    }
+                }
+            }
+            false => {
+                let target = profile.value_at(started.elapsed()).round() as usize;
+                concurrency_limit = resize_semaphore(&semaphore, concurrency_limit, target);
+
+                // Bounded wait so a stage ramping concurrency down to zero can't stall the loop.
+                match tokio::time::timeout(
+                    Duration::from_millis(100),
+                    semaphore.clone().acquire_owned(),
+                )
+                .await
+                {
+                    Ok(Ok(permit)) => (permit, None),
+                    _ => continue,
+                }
+            }
+        };
 
         let msg = rx.recv();
         if msg.is_err() {
@@ -191,162 +381,159 @@ pub async fn run(config: config::Config, requested_stop: Arc<AtomicBool>) -> Htt
             last_send_progress = Instant::now();
         }
 
-        match msg.unwrap() {
-            Action::ProcessURL(item) => {
-                if prevent_duplicate_requests && processed.contains(&item.url) {
+        let item = match msg.unwrap() {
+            Action::ProcessURL(item) => Some(item),
+            Action::RunScenario => None,
+            Action::Ping => continue,
+        };
+
+        if let Some(item) = item.as_ref() {
+            if prevent_duplicate_requests && processed.contains(&item.url) {
+                continue;
+            }
+        }
+
+        let row = match feeder.as_ref() {
+            Some(feeder) => match feeder.next() {
+                Some(row) => Some(row),
+                None => {
+                    println!(
+                        "{}",
+                        "Every row of the data file was used, stopping.".yellow()
+                    );
+                    should_process_work = false;
                     continue;
                 }
+            },
+            None => None,
+        };
 
-                total_processed += 1;
-
-                let tx = tx.clone();
-                let http_client = http_client.clone();
+        total_processed += 1;
 
-                if prevent_duplicate_requests {
-                    processed.insert(item.url.clone());
-                }
+        if let Some(item) = item.as_ref().filter(|_| prevent_duplicate_requests) {
+            processed.insert(item.url.clone());
+        }
 
-                let headers = headers.clone();
-                let basic_auth = basic_auth.clone();
-
-                tokio::task::spawn(execute(
-                    item,
-                    tx.clone(),
-                    http_client.clone(),
-                    results.clone(),
-                    permit,
-                    verbose,
-                    headers,
-                    mode,
-                    method.clone(),
-                    allowed_domains.clone(),
-                    basic_auth,
-                    random_arguments.clone(),
-                ));
+        if let Some(scheduled_at) = scheduled_at {
+            if scheduled_at.elapsed() > LATE_DISPATCH_THRESHOLD {
+                dispatch.late += 1;
             }
 
-            _ => {}
+            scheduled += 1;
         }
+
+        let stage = profile.stage_at(scheduled_at.unwrap_or_else(Instant::now) - started);
+
+        let worker = WorkerSlot::new(permit, &worker_ids);
+
+        match item {
+            Some(item) => tokio::task::spawn(execute(
+                item,
+                context.clone(),
+                worker,
+                scheduled_at,
+                stage,
+                row,
+            )),
+            None => tokio::task::spawn(scenario::execute(
+                context.clone(),
+                worker,
+                scheduled_at,
+                stage,
+                row,
+            )),
+        };
+    }
+
+    if let Some(log) = context.request_log.as_ref() {
+        log.finish();
     }
 
-    results
+    RunResults {
+        stats: context.stats.snapshot(),
+        dispatch,
+        connections: context.http_client.connections(),
+    }
 }
 
 pub async fn execute(
     item: UrlItem,
-    tx: Sender<Action>,
-    http_client: reqwest::Client,
-    results: HttpResultsHolder,
-    _permit: tokio::sync::OwnedSemaphorePermit,
-    verbose: bool,
-    headers: reqwest::header::HeaderMap,
-    mode: config::Mode,
-    method: reqwest::Method,
-    allowed_domains: config::AllowedDomains,
-    basic_auth: Option<config::BasicAuth>,
-    random_arguments: Option<regex::Regex>,
+    context: Arc<ExecuteContext>,
+    worker: WorkerSlot,
+    scheduled_at: Option<Instant>,
+    stage: Option<usize>,
+    row: Option<Arc<Vec<String>>>,
 ) {
-    let url = match random_arguments.as_ref() {
-        Some(r) => {
-            let mut url = item.url.to_string();
-
-            for c in r.captures_iter(&url.to_string()) {
-                let min = c.get(1).unwrap().as_str().parse::<u64>().unwrap();
-                let max = c.get(2).unwrap().as_str().parse::<u64>().unwrap();
-                let random_n: u64 = rand::thread_rng().gen_range(min..max);
-                url = url.replace(&c[0], &random_n.to_string());
-            }
-
-            Url::parse(&url).unwrap()
-        }
-        None => item.url.clone(),
+    let render_context = template::RenderContext {
+        worker_id: worker.id,
+        seq: context
+            .sequence
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
+        row,
+        ..Default::default()
     };
 
-    let start_time = Instant::now();
-
-    let mut resp = http_client.request(method, url.clone());
-    if let Some(basic_auth) = basic_auth {
-        resp = resp.basic_auth(basic_auth.username, basic_auth.password);
-    }
-
-    let headers = match random_arguments.as_ref() {
-        Some(r) => {
-            let mut headers = headers.clone();
-
-            for value in headers.values_mut() {
-                if let Some(c) = r.captures(value.to_str().unwrap()) {
-                    let min = c.get(1).unwrap().as_str().parse::<usize>().unwrap();
-                    let max = c.get(2).unwrap().as_str().parse::<usize>().unwrap();
-                    let random_n = rand::thread_rng().gen_range(min..max);
-                    *value = reqwest::header::HeaderValue::from_str(
-                        &value
-                            .to_str()
-                            .unwrap()
-                            .replace(&c[0], &random_n.to_string()),
-                    )
-                    .unwrap();
+    let url = match context
+        .templates
+        .as_ref()
+        .and_then(|templates| templates.urls.get(&item.url))
+    {
+        Some(template) => {
+            let rendered = template.render_url(&render_context);
+
+            match Url::parse(&rendered) {
+                Ok(url) => url,
+                Err(e) => {
+                    error!("{} -> {}", rendered.red(), e.to_string().magenta());
+                    return;
                 }
             }
-
-            headers
         }
-        None => headers,
+        None => item.url.clone(),
     };
 
-    let resp = resp.headers(headers).send().await;
+    let body = match context.body.as_ref() {
+        Some(body) if !body.is_static() => body
+            .encode(|template| template.render(&render_context))
+            .into(),
+        _ => context.encoded_body.clone(),
+    };
 
-    let duration = start_time.elapsed();
+    let group = context.grouping.as_ref().map(|grouping| grouping.key(&url));
 
-    if resp.is_err() {
-        let err = resp.err().unwrap();
-        error!("{url}: {}", err.to_string().red());
-        results.lock().unwrap().push(HttpResult::Error(err));
-        return;
-    }
+    let resp = send(
+        &context,
+        worker.id,
+        client::Request {
+            method: context.method.clone(),
+            url: url.clone(),
+            headers: request_headers(&context, &render_context),
+            body,
+        },
+        scheduled_at,
+        stage,
+        group,
+        &[],
+    )
+    .await;
+
+    let resp = match resp {
+        Some(resp) => resp,
+        None => return,
+    };
 
-    let resp = resp.unwrap();
-    let content_type = resp.headers().get("Content-Type");
-    let content_type = match content_type {
-        Some(ct) => ct.to_str().unwrap().to_string(),
+    let content_type = match resp.headers.get("Content-Type") {
+        Some(ct) => ct.to_str().unwrap_or_default().to_string(),
         None => "".to_string(),
     };
-    let status = resp.status();
-
-    let bytes = resp.bytes().await;
+    let status = resp.status;
+    let bytes = resp.body;
 
-    if bytes.is_err() {
-        let err = bytes.err().unwrap();
-        error!("{url}: {}", err.to_string().red());
-        results.lock().unwrap().push(HttpResult::Error(err));
+    if context.mode == config::Mode::Single {
         return;
     }
 
-    let bytes = bytes.unwrap();
-    let length = bytes.len();
-
-    if verbose {
-        println!(
-            "{}: {} in {:.5}s",
-            url.to_string().blue(),
-            status,
-            duration.as_secs_f32().to_string().yellow()
-        );
-    }
-
-    results
-        .lock()
-        .unwrap()
-        .push(HttpResult::Response(HttpResponse {
-            status,
-            duration,
-            length: length as usize,
-        }));
-
-    if mode == config::Mode::Single {
-        return;
-    }
-
-    if content_type == "" || !content_type.starts_with("text/html") {
+    if content_type.is_empty() || !content_type.starts_with("text/html") {
         return;
     }
 
@@ -368,7 +555,7 @@ pub async fn execute(
         .find(Name("a"))
         .filter_map(|n| match n.attr("href") {
             None => None,
-            Some(href) => get_valid_url(href, &item, &allowed_domains),
+            Some(href) => get_valid_url(href, &item, &context.allowed_domains),
         })
         .collect();
 
@@ -378,8 +565,215 @@ pub async fn execute(
             false => url.clone(),
         };
 
-        let _r = tx.send(Action::ProcessURL(UrlItem { parent, url }));
+        let _r = context.tx.send(Action::ProcessURL(UrlItem { parent, url }));
+    }
+}
+
+/// The --header values, with their templates rendered.
+pub fn request_headers(
+    context: &ExecuteContext,
+    render_context: &template::RenderContext,
+) -> hyper::header::HeaderMap {
+    let mut headers = context.headers.clone();
+
+    for (name, template) in context
+        .templates
+        .iter()
+        .flat_map(|templates| templates.headers.iter())
+    {
+        match hyper::header::HeaderValue::from_str(&template.render(render_context)) {
+            Ok(value) => {
+                headers.insert(name.clone(), value);
+            }
+            Err(e) => error!("{name}: {}", e.to_string().red()),
+        }
     }
+
+    headers
+}
+
+/// Sends a request and records its result under `group`, returning the response unless the
+/// request failed. Responses are checked against the --expect-* checks and `checks`.
+pub async fn send(
+    context: &ExecuteContext,
+    worker_id: usize,
+    mut request: client::Request,
+    scheduled_at: Option<Instant>,
+    stage: Option<usize>,
+    group: Option<String>,
+    checks: &[checks::Check],
+) -> Option<client::Response> {
+    let method = request.method.clone();
+    let url = request.url.clone();
+
+    let record = |sent_at, duration, result: HttpResult| {
+        if let Some(log) = context.request_log.as_ref() {
+            log.write(request_log::RequestRecord::new(
+                sent_at, worker_id, &method, &url, duration, &result,
+            ));
+        }
+
+        context.stats.record(stage, group.as_deref(), &result);
+    };
+
+    if let Some(basic_auth) = &context.basic_auth {
+        request
+            .headers
+            .entry(hyper::header::AUTHORIZATION)
+            .or_insert_with(|| {
+                client::basic_auth(&basic_auth.username, basic_auth.password.as_deref())
+            });
+    }
+
+    // Tokens given with --header take precedence, like the basic auth header.
+    let mut bearer = None;
+    if let Some(provider) = context.bearer.as_ref() {
+        if !request.headers.contains_key(hyper::header::AUTHORIZATION) {
+            bearer = provider.header(&context.http_client).await;
+
+            if let Some(token) = bearer.as_ref() {
+                request
+                    .headers
+                    .insert(hyper::header::AUTHORIZATION, token.clone());
+            }
+        }
+    }
+
+    let cookies = context.cookies.get(worker_id);
+    let refreshes_token = matches!(
+        (context.bearer.as_ref(), bearer.as_ref()),
+        (Some(provider), Some(_)) if provider.refreshes()
+    );
+
+    // Requests are signed by the client, so a retry gets a signature of its own.
+    let retry = match refreshes_token || !context.signers.is_empty() {
+        true => Some(request.clone()),
+        false => None,
+    };
+
+    let sent_at = std::time::SystemTime::now();
+    let start_time = Instant::now();
+
+    let result = context
+        .http_client
+        .send_signed(request, cookies, &context.signers)
+        .await;
+    let mut retried = false;
+
+    // Expired tokens are refreshed and Digest challenges answered, then the request is sent once
+    // more. The result covers both round trips.
+    let result = match (result, retry) {
+        (Ok(first), Some(mut retry)) if first.status == hyper::StatusCode::UNAUTHORIZED => {
+            match reauthenticate(context, &first, &mut retry, bearer).await {
+                true => {
+                    retried = true;
+                    let waited = start_time.elapsed();
+
+                    context
+                        .http_client
+                        .send_signed(retry, cookies, &context.signers)
+                        .await
+                        .map(|second| second.retry_of(&first, waited))
+                }
+                false => Ok(first),
+            }
+        }
+        (result, _) => result,
+    };
+
+    let resp = match result {
+        Ok(resp) => resp,
+        Err(err) => {
+            error!("{url}: {}", err.to_string().red());
+            record(sent_at, start_time.elapsed(), HttpResult::Error(err));
+            return None;
+        }
+    };
+
+    let duration = resp.duration;
+    let failed_checks = checks::failed(context.checks.iter().chain(checks), &resp);
+
+    if context.verbose {
+        println!(
+            "{}: {} in {:.5}s",
+            url.to_string().blue(),
+            resp.status,
+            duration.as_secs_f32().to_string().yellow()
+        );
+    }
+
+    for check in failed_checks.iter() {
+        error!("{url}: {} {}", "check failed:".red(), check.magenta());
+    }
+
+    record(
+        sent_at,
+        duration,
+        HttpResult::Response(HttpResponse {
+            status: resp.status,
+            duration,
+            scheduled_duration: scheduled_at
+                .map(|scheduled_at| start_time.duration_since(scheduled_at) + duration),
+            length: resp.body.len(),
+            timings: resp.timings.clone(),
+            version: resp.version,
+            connections_reused: resp.connections_reused,
+            retried,
+            failed_checks,
+        }),
+    );
+
+    Some(resp)
+}
+
+/// Gets new credentials after a 401 response, returns true when the request is worth sending again.
+async fn reauthenticate(
+    context: &ExecuteContext,
+    response: &client::Response,
+    request: &mut client::Request,
+    bearer: Option<hyper::header::HeaderValue>,
+) -> bool {
+    if let (Some(provider), Some(token)) = (context.bearer.as_ref(), bearer) {
+        if provider.refreshes() {
+            provider.reject(&token).await;
+
+            if let Some(token) = provider.header(&context.http_client).await {
+                request.headers.insert(hyper::header::AUTHORIZATION, token);
+                return true;
+            }
+        }
+    }
+
+    // Every signer gets to see the challenge.
+    context
+        .signers
+        .iter()
+        .filter(|signer| signer.challenged(response))
+        .count()
+        > 0
+}
+
+/// Grows or shrinks the total number of permits towards `target` and returns the new total.
+/// Permits held by in-flight requests can't be revoked, so shrinking may take several calls.
+fn resize_semaphore(semaphore: &Semaphore, current: usize, target: usize) -> usize {
+    if target > current {
+        semaphore.add_permits(target - current);
+        return target;
+    }
+
+    let mut current = current;
+
+    while current > target {
+        match semaphore.try_acquire() {
+            Ok(permit) => {
+                permit.forget();
+                current -= 1;
+            }
+            Err(_) => break,
+        }
+    }
+
+    current
 }
 
 fn get_valid_url(
@@ -413,7 +807,7 @@ fn get_valid_url(
     }
 
     match Url::parse(&input) {
-        Ok(url) => match is_allowed_host(&url, &allowed_domains) {
+        Ok(url) => match is_allowed_host(&url, allowed_domains) {
             true => Some(url),
             false => None,
         },
@@ -444,6 +838,72 @@ fn is_allowed_host(url: &Url, allowed_domains: &config::AllowedDomains) -> bool
 
 #[cfg(test)]
 mod tests {
+    use crate::test_server::{ok, RawServer};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn context(signers: Vec<Arc<dyn crate::signers::Signer>>) -> super::ExecuteContext {
+        super::ExecuteContext {
+            tx: std::sync::mpsc::channel().0,
+            http_client: crate::client::HttpClient::builder().build().unwrap(),
+            stats: crate::stats::Recorder::new(1),
+            verbose: false,
+            headers: Default::default(),
+            mode: crate::config::Mode::Single,
+            method: hyper::Method::GET,
+            allowed_domains: crate::config::AllowedDomains::All,
+            basic_auth: None,
+            bearer: None,
+            signers,
+            cookies: Default::default(),
+            templates: None,
+            sequence: Default::default(),
+            body: None,
+            encoded_body: Default::default(),
+            request_log: None,
+            grouping: None,
+            scenario: None,
+            checks: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn records_challenged_requests_with_both_round_trips() {
+        let server = RawServer::tcp(Duration::from_millis(50), |index| match index {
+            0 => "HTTP/1.1 401 Unauthorized\r\nWWW-Authenticate: Digest realm=\"x\", nonce=\"abc\", qop=\"auth\"\r\nContent-Length: 0\r\n\r\n".to_string(),
+            _ => ok("ok"),
+        })
+        .await;
+        let context = context(vec![Arc::new(crate::signers::DigestAuth::new(
+            "user", "pass",
+        ))]);
+
+        let request = crate::client::Request {
+            method: hyper::Method::GET,
+            url: server.url.parse().unwrap(),
+            headers: Default::default(),
+            body: Default::default(),
+        };
+        let response = super::send(&context, 0, request, None, None, None, &[])
+            .await
+            .unwrap();
+        assert_eq!(response.status, hyper::StatusCode::OK);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests[1].contains("authorization: Digest username=\"user\""));
+
+        let stats = context.stats.snapshot();
+        assert_eq!(stats.total.total(), 1);
+        assert_eq!(stats.total.status_codes.get(&200), Some(&1));
+        assert_eq!(stats.total.retried, 1);
+        // The second request went out on the connection of the first.
+        assert_eq!(stats.total.connections_reused, 1);
+        assert_eq!(context.http_client.connections().opened, 1);
+        assert!(stats.total.latency.min() >= 100_000);
+        assert!(stats.phases.ttfb.min() >= 100_000);
+    }
+
     #[test]
     fn test_discover_limit_urls_per_allowed() {
         let allowed_domains = crate::config::allowed_domains_from_config(
@@ -0,0 +1,210 @@
+use super::*;
+
+/// Load level over time: a starting value (concurrency or arrival rate) followed by
+/// `--stages` that linearly ramp towards their target.
+#[derive(Debug, Clone)]
+pub struct LoadProfile {
+    start: f64,
+    stages: Vec<config::Stage>,
+}
+
+impl LoadProfile {
+    pub fn new(start: f64, stages: Option<Vec<config::Stage>>) -> Self {
+        LoadProfile {
+            start,
+            stages: stages.unwrap_or_default(),
+        }
+    }
+
+    /// The profile of a run: --stages starting from --rate, or from --concurrent without it.
+    pub fn from_config(config: &config::Config) -> Self {
+        LoadProfile::new(
+            config.rate.unwrap_or(config.concurrent as f64),
+            config.stages.clone(),
+        )
+    }
+
+    /// Index of the stage running at `elapsed`, `None` when no stages are configured.
+    /// Once every stage has finished the last one is reported.
+    pub fn stage_at(&self, elapsed: Duration) -> Option<usize> {
+        if self.stages.is_empty() {
+            return None;
+        }
+
+        let mut stage_start = Duration::ZERO;
+
+        for (index, stage) in self.stages.iter().enumerate() {
+            stage_start += stage.duration;
+            if elapsed < stage_start {
+                return Some(index);
+            }
+        }
+
+        Some(self.stages.len() - 1)
+    }
+
+    /// Highest concurrency or rate the profile ever reaches.
+    pub fn max_value(&self) -> f64 {
+        self.stages
+            .iter()
+            .map(|stage| stage.target)
+            .fold(self.start, f64::max)
+    }
+
+    /// Target concurrency or rate at `elapsed`.
+    pub fn value_at(&self, elapsed: Duration) -> f64 {
+        let mut from = self.start;
+        let mut stage_start = Duration::ZERO;
+
+        for stage in self.stages.iter() {
+            if elapsed < stage_start + stage.duration {
+                let progress = (elapsed - stage_start).as_secs_f64() / stage.duration.as_secs_f64();
+                return from + (stage.target - from) * progress;
+            }
+
+            from = stage.target;
+            stage_start += stage.duration;
+        }
+
+        from
+    }
+
+    /// Average concurrency or rate between `from` and `to`.
+    pub fn mean_value(&self, from: Duration, to: Duration) -> f64 {
+        match to > from {
+            true => (self.area_until(to) - self.area_until(from)) / (to - from).as_secs_f64(),
+            false => self.value_at(from),
+        }
+    }
+
+    /// Integral of the profile from the start of the run until `elapsed`.
+    fn area_until(&self, elapsed: Duration) -> f64 {
+        let mut area = 0.0;
+        let mut from = self.start;
+        let mut stage_start = Duration::ZERO;
+
+        for stage in self.stages.iter() {
+            if elapsed < stage_start + stage.duration {
+                let length = (elapsed - stage_start).as_secs_f64();
+                return area + (from + self.value_at(elapsed)) / 2.0 * length;
+            }
+
+            area += (from + stage.target) / 2.0 * stage.duration.as_secs_f64();
+            from = stage.target;
+            stage_start += stage.duration;
+        }
+
+        area + from * (elapsed - stage_start).as_secs_f64()
+    }
+
+    /// Offset from the start of the run at which request number `n` (starting at 0) is due when
+    /// the profile describes an arrival rate. This integrates the piecewise linear rate, so ramps
+    /// are followed exactly whatever the spacing between requests.
+    /// Returns `None` when the rate drops to zero and no further requests are due.
+    pub fn dispatch_offset(&self, n: u64) -> Option<Duration> {
+        let mut remaining = n as f64;
+        let mut from = self.start;
+        let mut stage_start = Duration::ZERO;
+
+        for stage in self.stages.iter() {
+            let length = stage.duration.as_secs_f64();
+            let stage_requests = (from + stage.target) / 2.0 * length;
+
+            if remaining < stage_requests {
+                let slope = (stage.target - from) / length;
+
+                // Solve from * t + slope * t^2 / 2 = remaining for t.
+                let offset = if slope.abs() < f64::EPSILON {
+                    remaining / from
+                } else {
+                    let discriminant = (from * from + 2.0 * slope * remaining).max(0.0);
+                    (discriminant.sqrt() - from) / slope
+                };
+
+                return Some(stage_start + Duration::from_secs_f64(offset.clamp(0.0, length)));
+            }
+
+            remaining -= stage_requests;
+            from = stage.target;
+            stage_start += stage.duration;
+        }
+
+        if from <= 0.0 {
+            return None;
+        }
+
+        Some(stage_start + Duration::from_secs_f64(remaining / from))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoadProfile;
+    use crate::config::Stage;
+    use std::time::Duration;
+
+    fn stage(secs: u64, target: f64) -> Stage {
+        Stage {
+            duration: Duration::from_secs(secs),
+            target,
+        }
+    }
+
+    #[test]
+    fn ramps_between_stage_targets() {
+        let profile = LoadProfile::new(
+            0.0,
+            Some(vec![stage(10, 100.0), stage(10, 100.0), stage(10, 0.0)]),
+        );
+
+        assert_eq!(profile.value_at(Duration::from_secs(0)), 0.0);
+        assert_eq!(profile.value_at(Duration::from_secs(5)), 50.0);
+        assert_eq!(profile.value_at(Duration::from_secs(15)), 100.0);
+        assert_eq!(profile.value_at(Duration::from_secs(25)), 50.0);
+        assert_eq!(profile.value_at(Duration::from_secs(60)), 0.0);
+
+        assert_eq!(profile.stage_at(Duration::from_secs(3)), Some(0));
+        assert_eq!(profile.stage_at(Duration::from_secs(10)), Some(1));
+        assert_eq!(profile.stage_at(Duration::from_secs(60)), Some(2));
+        assert_eq!(LoadProfile::new(2.0, None).stage_at(Duration::ZERO), None);
+
+        assert_eq!(profile.max_value(), 100.0);
+        assert_eq!(LoadProfile::new(2.0, None).max_value(), 2.0);
+
+        assert_eq!(
+            profile.mean_value(Duration::ZERO, Duration::from_secs(10)),
+            50.0
+        );
+        assert_eq!(
+            profile.mean_value(Duration::from_secs(10), Duration::from_secs(30)),
+            75.0
+        );
+        assert_eq!(
+            LoadProfile::new(4.0, None).mean_value(Duration::ZERO, Duration::from_secs(3)),
+            4.0
+        );
+    }
+
+    #[test]
+    fn dispatch_offsets_follow_rate() {
+        let constant = LoadProfile::new(100.0, None);
+        assert_eq!(constant.dispatch_offset(0), Some(Duration::ZERO));
+        assert_eq!(
+            constant.dispatch_offset(250),
+            Some(Duration::from_millis(2500))
+        );
+
+        // Ramp 0 -> 10/s over 10s sends 50 requests, then back down to 0 over another 10s.
+        let ramp = LoadProfile::new(0.0, Some(vec![stage(10, 10.0), stage(10, 0.0)]));
+        let offset = ramp.dispatch_offset(50).unwrap();
+        assert!((offset.as_secs_f64() - 10.0).abs() < 1e-6);
+
+        // 12.5 requests are due in the first half of the ramp.
+        let offset = ramp.dispatch_offset(12).unwrap();
+        assert!(offset < Duration::from_secs(5));
+
+        // The ramp down sends another 50 requests, after which nothing is due.
+        assert!(ramp.dispatch_offset(99).is_some());
+        assert_eq!(ramp.dispatch_offset(100), None);
+    }
+}
@@ -0,0 +1,595 @@
+use super::*;
+use hdrhistogram::Histogram;
+
+/// Percentiles reported for every latency histogram, along with their labels.
+pub const PERCENTILES: [(&str, f64); 6] = [
+    ("50th", 0.5),
+    ("75th", 0.75),
+    ("90th", 0.9),
+    ("95th", 0.95),
+    ("99th", 0.99),
+    ("99.9th", 0.999),
+];
+
+/// Latency distribution in microseconds.
+#[derive(Debug, Clone)]
+pub struct LatencyStats {
+    pub min: u64,
+    pub max: u64,
+    pub mean: f64,
+    pub stddev: f64,
+    /// Values for each entry of `PERCENTILES`, in the same order.
+    pub percentiles: Vec<u64>,
+}
+
+impl LatencyStats {
+    /// Returns `None` when nothing was recorded, as there is no meaningful distribution.
+    pub fn from_histogram(histogram: &Histogram<u64>) -> Option<Self> {
+        if histogram.is_empty() {
+            return None;
+        }
+
+        Some(LatencyStats {
+            min: histogram.min(),
+            max: histogram.max(),
+            mean: histogram.mean(),
+            stddev: histogram.stdev(),
+            percentiles: PERCENTILES
+                .iter()
+                .map(|(_, quantile)| histogram.value_at_quantile(*quantile))
+                .collect(),
+        })
+    }
+}
+
+/// How latency is corrected for coordinated omission.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Correction {
+    /// In rate mode latency is already measured from the scheduled send time.
+    Scheduled,
+    /// In closed-loop mode each worker is expected to send once per this many microseconds, the
+    /// average concurrency divided by the throughput. Responses slower than twice the interval
+    /// are back-filled HdrHistogram style with the requests the worker couldn't send meanwhile.
+    Interval(u64),
+}
+
+impl Correction {
+    /// The correction of `requests` sent during `window` by `concurrency` workers on average.
+    pub fn new(rate_mode: bool, concurrency: f64, window: Duration, requests: u64) -> Self {
+        match rate_mode {
+            true => Correction::Scheduled,
+            false => Correction::Interval(
+                (window.as_secs_f64() * concurrency * 1_000_000.0 / requests.max(1) as f64) as u64,
+            ),
+        }
+    }
+
+    /// The correction of the whole run.
+    pub fn for_run(config: &config::Config, run: &process::RunResults, elapsed: Duration) -> Self {
+        let profile = profile::LoadProfile::from_config(config);
+
+        Correction::new(
+            config.rate.is_some(),
+            profile.mean_value(Duration::ZERO, elapsed),
+            elapsed,
+            run.stats.total.total(),
+        )
+    }
+
+    /// The correction of each stage, a stage cut short by the end of the run only covers its
+    /// elapsed part.
+    pub fn for_stages(
+        config: &config::Config,
+        run: &process::RunResults,
+        elapsed: Duration,
+    ) -> Vec<Self> {
+        let profile = profile::LoadProfile::from_config(config);
+        let mut stage_start = Duration::ZERO;
+
+        config
+            .stages
+            .iter()
+            .flatten()
+            .enumerate()
+            .map(|(index, stage)| {
+                let from = stage_start.min(elapsed);
+                stage_start += stage.duration;
+                let to = stage_start.min(elapsed);
+
+                Correction::new(
+                    config.rate.is_some(),
+                    profile.mean_value(from, to),
+                    to - from,
+                    run.stats
+                        .stages
+                        .get(index)
+                        .map_or(0, |summary| summary.total()),
+                )
+            })
+            .collect()
+    }
+
+    pub fn apply(&self, summary: &stats::Summary) -> Histogram<u64> {
+        match self {
+            Correction::Scheduled => summary.scheduled_latency.clone(),
+            Correction::Interval(interval) => summary.latency.clone_correct(*interval),
+        }
+    }
+}
+
+pub fn print(config: &config::Config, run: &process::RunResults, elapsed: Duration) {
+    let summary = &run.stats.total;
+    let http_responses = summary.responses;
+    let errors = summary.error_count();
+
+    println!();
+
+    println!(
+        "*** Processed a total of {} requests in {:.2} seconds!",
+        summary.total().to_string().green(),
+        elapsed.as_secs_f32()
+    );
+
+    let percentage_responses = percentage(http_responses, summary.total());
+    let percentage_failures = percentage(errors, summary.total());
+
+    println!(
+        "*** Received {} HTTP responses ({:.2}%) while {} requests failed ({:.2}%).\n",
+        http_responses.to_string().green(),
+        percentage_responses,
+        errors.to_string().red(),
+        percentage_failures,
+    );
+
+    let mut status_codes: Vec<_> = summary.status_codes.iter().collect();
+    status_codes.sort_by_key(|a| a.1);
+    status_codes.reverse();
+
+    for (status, count) in status_codes.iter() {
+        println!(
+            "* [status {}] : {} requests ({:.2}%)",
+            match status {
+                200 => status.to_string().green(),
+                301 => status.to_string().yellow(),
+                _ => status.to_string().red(),
+            },
+            count.to_string().green(),
+            percentage(**count, http_responses),
+        );
+    }
+
+    for (kind, count) in summary.errors.iter() {
+        println!(
+            "* [error {}] : {} requests ({:.2}%)",
+            kind.as_str().red(),
+            count.to_string().red(),
+            percentage(*count, summary.total()),
+        );
+    }
+
+    println!();
+
+    let checks = configured_checks(config);
+    if !checks.is_empty() {
+        print_checks(&checks, summary);
+    }
+
+    match config.rate {
+        Some(rate) => {
+            println!("* Target arrival rate: {rate:.2} [#/sec]");
+            println!("* Max in-flight requests: {}", config.concurrent);
+            println!(
+                "* Dropped dispatches (no free in-flight slot): {}",
+                match run.dispatch.dropped {
+                    0 => "0".green(),
+                    dropped => dropped.to_string().red(),
+                }
+            );
+            println!(
+                "* Late dispatches (behind schedule): {}",
+                match run.dispatch.late {
+                    0 => "0".green(),
+                    late => late.to_string().yellow(),
+                }
+            );
+        }
+        None => println!("* Concurrency level: {}", config.concurrent),
+    }
+
+    println!(
+        "* Requests per second: {:.2} [#/sec] (mean)",
+        (http_responses as f32 / elapsed.as_secs_f32())
+    );
+
+    println!(
+        "* Total content body length of responses: {} bytes",
+        summary.body_length
+    );
+
+    if !summary.protocols.is_empty() {
+        println!(
+            "* Protocols: {}",
+            summary
+                .protocols
+                .iter()
+                .map(|(protocol, count)| format!(
+                    "{protocol} {:.2}%",
+                    percentage(*count, http_responses)
+                ))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    println!(
+        "* Connections opened: {}, reused: {} ({:.2}% of requests reused a connection)",
+        run.connections.opened,
+        summary.connections_reused,
+        percentage(
+            summary.connections_reused,
+            run.connections.opened + summary.connections_reused
+        )
+    );
+
+    if !run.connections.tls.is_empty() {
+        println!(
+            "* TLS handshakes: {}",
+            run.connections
+                .tls
+                .iter()
+                .map(|(session, count)| format!("{} {} {count}", session.version, session.cipher))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    if summary.retried > 0 {
+        println!(
+            "* Sent again after a 401 with new credentials: {} requests ({:.2}%), timed over both round trips",
+            summary.retried.to_string().yellow(),
+            percentage(summary.retried, http_responses)
+        );
+    }
+
+    println!();
+
+    let correction = Correction::for_run(config, run, elapsed);
+    let raw = LatencyStats::from_histogram(&summary.latency);
+    let corrected = LatencyStats::from_histogram(&correction.apply(summary));
+
+    match (raw, corrected) {
+        (Some(raw), Some(corrected)) => {
+            match correction {
+                Correction::Interval(interval) => println!(
+                    "* Corrected latency assumes one request per {} per worker (concurrency / throughput).",
+                    format_micros(interval)
+                ),
+                Correction::Scheduled => {
+                    println!("* Corrected latency is measured from the scheduled send time.")
+                }
+            }
+
+            println!("* Response times (raw / corrected for coordinated omission):");
+            print_latency_table(&raw, &corrected);
+        }
+        _ => println!(
+            "* {}",
+            "No HTTP responses received, response time statistics are unavailable.".yellow()
+        ),
+    }
+
+    println!();
+
+    if !summary.latency.is_empty() {
+        print_phases(&run.stats.phases);
+    }
+
+    if let Some(stages) = config.stages.as_ref() {
+        print_stages(
+            stages,
+            &run.stats.stages,
+            &Correction::for_stages(config, run, elapsed),
+        );
+    }
+
+    // Workers spread their requests over every group, so groups share the run's send interval.
+    if let Some(grouping) = config.grouping.as_ref() {
+        print_groups(grouping, &run.stats, correction);
+    }
+}
+
+/// Names of the --expect-* checks and the checks of every scenario step.
+pub fn configured_checks(config: &config::Config) -> Vec<&str> {
+    config
+        .checks
+        .iter()
+        .chain(
+            config
+                .scenario
+                .iter()
+                .flat_map(|scenario| scenario.steps.iter())
+                .flat_map(|step| step.checks.iter()),
+        )
+        .map(|check| check.name.as_str())
+        .collect()
+}
+
+fn print_checks(checks: &[&str], summary: &stats::Summary) {
+    println!(
+        "*** Checks: {} of {} responses ({:.2}%) failed at least one check.",
+        match summary.failed_checks {
+            0 => "0".green(),
+            failed => failed.to_string().red(),
+        },
+        summary.responses,
+        percentage(summary.failed_checks, summary.responses),
+    );
+
+    for check in checks.iter() {
+        let failed = summary.checks.get(*check).copied().unwrap_or(0);
+
+        println!(
+            "* [check {}] : {} failed ({:.2}%)",
+            check.blue(),
+            match failed {
+                0 => "0".green(),
+                failed => failed.to_string().red(),
+            },
+            percentage(failed, summary.responses),
+        );
+    }
+
+    println!();
+}
+
+/// Groups ordered according to --group-sort.
+pub fn sorted_groups<'a>(
+    grouping: &config::Grouping,
+    stats: &'a stats::RunStats,
+) -> Vec<(&'a String, &'a stats::Summary)> {
+    let mut groups: Vec<_> = stats.groups.iter().collect();
+
+    match grouping.sort {
+        config::GroupSort::Slowest => groups.sort_by_key(|(key, summary)| {
+            (
+                std::cmp::Reverse(summary.latency.value_at_quantile(0.95)),
+                *key,
+            )
+        }),
+        config::GroupSort::Count => {
+            groups.sort_by_key(|(key, summary)| (std::cmp::Reverse(summary.total()), *key))
+        }
+        config::GroupSort::Errors => groups.sort_by(|(a_key, a), (b_key, b)| {
+            error_rate(b)
+                .total_cmp(&error_rate(a))
+                .then_with(|| a_key.cmp(b_key))
+        }),
+        config::GroupSort::Url => groups.sort_by_key(|(key, _)| *key),
+    }
+
+    groups
+}
+
+/// Percentage of requests that failed without an HTTP response.
+pub fn error_rate(summary: &stats::Summary) -> f64 {
+    percentage(summary.error_count(), summary.total())
+}
+
+fn print_groups(grouping: &config::Grouping, stats: &stats::RunStats, correction: Correction) {
+    let groups = sorted_groups(grouping, stats);
+
+    println!(
+        "*** Breakdown by URL ({} groups, sorted by {:?}, response times raw / corrected):",
+        groups.len(),
+        grouping.sort
+    );
+
+    for (key, summary) in groups.iter().take(grouping.limit) {
+        let mut status_codes: Vec<_> = summary.status_codes.iter().collect();
+        status_codes.sort_by_key(|(status, count)| (std::cmp::Reverse(**count), **status));
+
+        let status_codes = status_codes
+            .iter()
+            .map(|(status, count)| format!("{status} x{count}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        let latency = latency_summary(summary, correction);
+
+        let failed_checks = match summary.failed_checks {
+            0 => String::new(),
+            failed => format!(", {} failed checks", failed.to_string().red()),
+        };
+
+        println!(
+            "* {} : {} requests, {} errors, [{}]{failed_checks}, {latency}",
+            key.blue(),
+            summary.total().to_string().green(),
+            match summary.error_count() {
+                0 => "0.00%".green(),
+                _ => format!("{:.2}%", error_rate(summary)).red(),
+            },
+            status_codes,
+        );
+    }
+
+    if groups.len() > grouping.limit {
+        println!(
+            "* ... {} more groups not shown (see --group-limit)",
+            groups.len() - grouping.limit
+        );
+    }
+
+    println!();
+}
+
+fn print_latency_table(raw: &LatencyStats, corrected: &LatencyStats) {
+    let row = |label: &str, raw: String, corrected: String| {
+        println!("  {label:>6}: {} / {}", raw.green(), corrected.yellow());
+    };
+
+    row("min", format_micros(raw.min), format_micros(corrected.min));
+
+    for (index, (label, _)) in PERCENTILES.iter().enumerate() {
+        row(
+            label,
+            format_micros(raw.percentiles[index]),
+            format_micros(corrected.percentiles[index]),
+        );
+    }
+
+    row("max", format_micros(raw.max), format_micros(corrected.max));
+    row(
+        "mean",
+        format_micros_f64(raw.mean),
+        format_micros_f64(corrected.mean),
+    );
+    row(
+        "stddev",
+        format_micros_f64(raw.stddev),
+        format_micros_f64(corrected.stddev),
+    );
+}
+
+fn print_phases(phases: &stats::Phases) {
+    println!("* Request phases (dns, connect and tls only for requests opening a connection):");
+
+    for (name, histogram) in phases.iter() {
+        match LatencyStats::from_histogram(histogram) {
+            Some(latency) => println!(
+                "  {name:>8}: p50 {}, p90 {}, p95 {}, p99 {}, max {} ({} samples)",
+                format_micros(latency.percentiles[0]).green(),
+                format_micros(latency.percentiles[2]).green(),
+                format_micros(latency.percentiles[3]).green(),
+                format_micros(latency.percentiles[4]).yellow(),
+                format_micros(latency.max).yellow(),
+                histogram.len(),
+            ),
+            None => println!("  {name:>8}: no samples"),
+        }
+    }
+
+    println!();
+}
+
+/// Raw and corrected p50, p95 and p99 of a stage or group.
+fn latency_summary(summary: &stats::Summary, correction: Correction) -> String {
+    let raw = LatencyStats::from_histogram(&summary.latency);
+    let corrected = LatencyStats::from_histogram(&correction.apply(summary));
+
+    match (raw, corrected) {
+        (Some(raw), Some(corrected)) => [("p50", 0), ("p95", 3), ("p99", 4)]
+            .iter()
+            .map(|(label, index)| {
+                format!(
+                    "{label} {} / {}",
+                    format_micros(raw.percentiles[*index]),
+                    format_micros(corrected.percentiles[*index])
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", "),
+        _ => "no responses".to_string(),
+    }
+}
+
+fn print_stages(
+    stages: &[config::Stage],
+    summaries: &[stats::Summary],
+    corrections: &[Correction],
+) {
+    println!("*** Stages breakdown (response times raw / corrected):");
+
+    for (index, stage) in stages.iter().enumerate() {
+        let summary = summaries.get(index).cloned().unwrap_or_default();
+        let latency = latency_summary(&summary, corrections[index]);
+
+        println!(
+            "* [stage {}] {}s -> {}: {} responses ({:.2} [#/sec]), {} errors, {latency}",
+            index + 1,
+            stage.duration.as_secs(),
+            stage.target,
+            summary.responses.to_string().green(),
+            summary.responses as f64 / stage.duration.as_secs_f64(),
+            match summary.error_count() {
+                0 => "0".green(),
+                errors => errors.to_string().red(),
+            },
+        );
+    }
+
+    println!();
+}
+
+pub fn percentage(count: u64, total: u64) -> f64 {
+    match total {
+        0 => 0.0,
+        total => count as f64 / total as f64 * 100.0,
+    }
+}
+
+pub fn format_micros(us: u64) -> String {
+    format_micros_f64(us as f64)
+}
+
+pub fn format_micros_f64(us: f64) -> String {
+    format!("{:.3}ms", us / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn latency_stats_from_histogram() {
+        let mut histogram = crate::stats::latency_histogram();
+        assert!(super::LatencyStats::from_histogram(&histogram).is_none());
+
+        for millis in 1..=100 {
+            crate::stats::record_latency(&mut histogram, Duration::from_millis(millis));
+        }
+
+        let stats = super::LatencyStats::from_histogram(&histogram).unwrap();
+
+        assert_eq!(stats.min, 1000);
+        assert!((49_950..=50_050).contains(&stats.percentiles[0]));
+        assert!((89_950..=90_050).contains(&stats.percentiles[2]));
+        assert!((99_900..=100_100).contains(&stats.max));
+        assert!((stats.mean - 50_500.0).abs() < 100.0);
+        assert!((stats.stddev - 28_866.0).abs() < 100.0);
+    }
+
+    #[test]
+    fn corrects_closed_loop_latency() {
+        use super::Correction;
+
+        // 2 workers over 1s sending 2000 requests, so one every 1ms per worker, without stalls.
+        let mut summary = crate::stats::Summary::default();
+        for index in 0..2000u64 {
+            let latency = Duration::from_micros(500 + index % 1000);
+            crate::stats::record_latency(&mut summary.latency, latency);
+        }
+
+        let correction = Correction::new(false, 2.0, Duration::from_secs(1), 2000);
+        assert_eq!(correction, Correction::Interval(1_000));
+
+        let corrected = correction.apply(&summary);
+        assert_eq!(corrected.len(), summary.latency.len());
+        assert_eq!(
+            corrected.value_at_quantile(0.99),
+            summary.latency.value_at_quantile(0.99)
+        );
+
+        // A 100ms stall held back the requests the worker would have sent, added at 99ms ... 1ms.
+        crate::stats::record_latency(&mut summary.latency, Duration::from_millis(100));
+        let corrected = correction.apply(&summary);
+        assert_eq!(corrected.len(), summary.latency.len() + 99);
+        assert!(corrected.value_at_quantile(0.99) > summary.latency.value_at_quantile(0.99));
+
+        // Rate mode uses the latency measured from the scheduled send times as is.
+        crate::stats::record_latency(&mut summary.scheduled_latency, Duration::from_millis(7));
+        let correction = Correction::new(true, 2.0, Duration::from_secs(1), 2000);
+        assert_eq!(correction, Correction::Scheduled);
+        assert_eq!(correction.apply(&summary).len(), 1);
+    }
+}
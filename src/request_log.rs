@@ -0,0 +1,215 @@
+use super::*;
+use serde::Serialize;
+use std::io::{BufWriter, Write};
+use std::sync::mpsc::{channel, Sender};
+use std::thread::JoinHandle;
+
+#[derive(clap::ArgEnum, Copy, Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Ndjson,
+    Csv,
+}
+
+/// One line of the per-request log.
+#[derive(Debug, Serialize)]
+pub struct RequestRecord {
+    /// Unix time in milliseconds at which the request was sent.
+    pub timestamp_ms: u64,
+    pub worker_id: usize,
+    pub method: String,
+    /// URL after %RAND(min,max)% expansion.
+    pub url: String,
+    pub status: Option<u16>,
+    /// Time until the response headers arrived, or until the request failed.
+    pub latency_us: u64,
+    pub body_length: Option<usize>,
+    pub error: Option<&'static str>,
+    /// Request phases, DNS, connect and TLS only when the request opened a new connection.
+    pub dns_us: Option<u64>,
+    pub connect_us: Option<u64>,
+    pub tls_us: Option<u64>,
+    pub ttfb_us: Option<u64>,
+    pub download_us: Option<u64>,
+    /// Names of the checks the response failed.
+    pub failed_checks: Vec<String>,
+    /// HTTP version of the response, ex: `HTTP/2`.
+    pub protocol: Option<&'static str>,
+}
+
+impl RequestRecord {
+    pub fn new(
+        sent_at: std::time::SystemTime,
+        worker_id: usize,
+        method: &hyper::Method,
+        url: &Url,
+        duration: Duration,
+        result: &process::HttpResult,
+    ) -> Self {
+        let (status, body_length, error, timings, failed_checks, protocol) = match result {
+            process::HttpResult::Response(val) => (
+                Some(val.status.as_u16()),
+                Some(val.length),
+                None,
+                Some(&val.timings),
+                val.failed_checks.clone(),
+                Some(stats::version_name(val.version)),
+            ),
+            process::HttpResult::Error(err) => {
+                (None, None, Some(err.kind().as_str()), None, vec![], None)
+            }
+        };
+        let micros = |duration: Duration| duration.as_micros() as u64;
+
+        RequestRecord {
+            timestamp_ms: sent_at
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|since| since.as_millis() as u64)
+                .unwrap_or(0),
+            worker_id,
+            method: method.to_string(),
+            url: url.to_string(),
+            status,
+            latency_us: duration.as_micros() as u64,
+            body_length,
+            error,
+            dns_us: timings.and_then(|t| t.dns).map(micros),
+            connect_us: timings.and_then(|t| t.connect).map(micros),
+            tls_us: timings.and_then(|t| t.tls).map(micros),
+            ttfb_us: timings.map(|t| micros(t.ttfb)),
+            download_us: timings.map(|t| micros(t.download)),
+            failed_checks,
+            protocol,
+        }
+    }
+
+    fn write_csv(&self, out: &mut impl Write) -> std::io::Result<()> {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}",
+            self.timestamp_ms,
+            self.worker_id,
+            self.method,
+            csv_field(&self.url),
+            optional(self.status),
+            self.latency_us,
+            optional(self.body_length),
+            self.error.unwrap_or_default(),
+            optional(self.dns_us),
+            optional(self.connect_us),
+            optional(self.tls_us),
+            optional(self.ttfb_us),
+            optional(self.download_us),
+            csv_field(&self.failed_checks.join(";")),
+            self.protocol.unwrap_or_default(),
+        )
+    }
+}
+
+const CSV_HEADER: &str = "timestamp_ms,worker_id,method,url,status,latency_us,body_length,error,dns_us,connect_us,tls_us,ttfb_us,download_us,failed_checks,protocol";
+
+enum Message {
+    Record(Box<RequestRecord>),
+    Finish,
+}
+
+/// Streams request records to a file from a background thread, keeping I/O off the request tasks.
+pub struct RequestLog {
+    tx: Sender<Message>,
+    writer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl RequestLog {
+    pub fn create(path: &str, format: LogFormat) -> anyhow::Result<Self> {
+        let mut out = BufWriter::new(std::fs::File::create(path)?);
+        let (tx, rx) = channel::<Message>();
+
+        if format == LogFormat::Csv {
+            writeln!(out, "{CSV_HEADER}")?;
+        }
+
+        let path = path.to_string();
+
+        let writer = std::thread::spawn(move || {
+            while let Ok(Message::Record(record)) = rx.recv() {
+                let written = match format {
+                    LogFormat::Ndjson => serde_json::to_writer(&mut out, &record)
+                        .map_err(std::io::Error::from)
+                        .and_then(|_| writeln!(out)),
+                    LogFormat::Csv => record.write_csv(&mut out),
+                };
+
+                if let Err(e) = written {
+                    error!("{} : {}", e.to_string().red(), path.magenta());
+                    return;
+                }
+            }
+
+            if let Err(e) = out.flush() {
+                error!("{} : {}", e.to_string().red(), path.magenta());
+            }
+        });
+
+        Ok(RequestLog {
+            tx,
+            writer: Mutex::new(Some(writer)),
+        })
+    }
+
+    pub fn write(&self, record: RequestRecord) {
+        let _r = self.tx.send(Message::Record(Box::new(record)));
+    }
+
+    /// Flushes everything written so far and stops the writer, later records are discarded.
+    pub fn finish(&self) {
+        let _r = self.tx.send(Message::Finish);
+
+        if let Some(writer) = self.writer.lock().unwrap().take() {
+            let _r = writer.join();
+        }
+    }
+}
+
+fn optional(value: Option<impl ToString>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn csv_records() {
+        let record = super::RequestRecord {
+            timestamp_ms: 1000,
+            worker_id: 3,
+            method: "GET".to_string(),
+            url: "https://example.com/?a=1,2".to_string(),
+            status: Some(200),
+            latency_us: 1500,
+            body_length: Some(42),
+            error: None,
+            dns_us: None,
+            connect_us: Some(300),
+            tls_us: None,
+            ttfb_us: Some(1200),
+            download_us: Some(50),
+            failed_checks: vec!["status in 2xx".to_string(), "latency <= 1ms".to_string()],
+            protocol: Some("HTTP/2"),
+        };
+
+        let mut out = Vec::new();
+        record.write_csv(&mut out).unwrap();
+
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "1000,3,GET,\"https://example.com/?a=1,2\",200,1500,42,,,300,,1200,50,status in 2xx;latency <= 1ms,HTTP/2\n"
+        );
+    }
+}
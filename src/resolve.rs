@@ -0,0 +1,217 @@
+use std::net::IpAddr;
+
+/// A --resolve mapping `host:port:addr[,addr]...`, used instead of DNS for that host and port.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Resolve {
+    pub host: String,
+    pub port: u16,
+    pub addresses: Vec<IpAddr>,
+}
+
+impl Resolve {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!("Invalid --resolve, should be host:port:addr[,addr] (ex: example.com:443:10.0.0.1): {input}")
+        };
+
+        let (host, rest) = split_host(input).ok_or_else(invalid)?;
+        let (port, addresses) = rest.split_once(':').ok_or_else(invalid)?;
+
+        if host.is_empty() {
+            return Err(invalid());
+        }
+
+        let addresses = addresses
+            .split(',')
+            .map(|address| {
+                address
+                    .trim()
+                    .trim_start_matches('[')
+                    .trim_end_matches(']')
+                    .parse::<IpAddr>()
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+
+        Ok(Resolve {
+            host: host.to_lowercase(),
+            port: port.parse().map_err(|_| invalid())?,
+            addresses,
+        })
+    }
+}
+
+/// A --connect-to mapping `host1:port1:host2:port2`. Connections for host1:port1 go to host2:port2
+/// while the Host header and TLS server name stay host1. Empty parts match any host or port, or
+/// keep the original one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectTo {
+    pub from_host: Option<String>,
+    pub from_port: Option<u16>,
+    pub to_host: Option<String>,
+    pub to_port: Option<u16>,
+}
+
+impl ConnectTo {
+    pub fn parse(input: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!("Invalid --connect-to, should be host1:port1:host2:port2 (ex: example.com:443:lb-2.internal:8443): {input}")
+        };
+
+        let (from_host, rest) = split_host(input).ok_or_else(invalid)?;
+        let (from_port, to) = rest.split_once(':').ok_or_else(invalid)?;
+        // The target can be an IPv6 address, only its last colon separates the port.
+        let (to_host, to_port) = to.rsplit_once(':').ok_or_else(invalid)?;
+        let to_host = to_host.trim_start_matches('[').trim_end_matches(']');
+
+        let port = |port: &str| match port {
+            "" => Ok(None),
+            port => port.parse::<u16>().map(Some).map_err(|_| invalid()),
+        };
+        let host = |host: &str| match host {
+            "" => None,
+            host => Some(host.to_lowercase()),
+        };
+
+        Ok(ConnectTo {
+            from_host: host(from_host),
+            from_port: port(from_port)?,
+            to_host: host(to_host),
+            to_port: port(to_port)?,
+        })
+    }
+}
+
+/// Splits a leading host from the rest of a mapping at the colon after it. IPv6 hosts are
+/// written in brackets, ex: `[::1]:443`, and returned without them.
+fn split_host(input: &str) -> Option<(&str, &str)> {
+    match input.strip_prefix('[') {
+        Some(bracketed) => {
+            let (host, rest) = bracketed.split_once(']')?;
+            Some((host, rest.strip_prefix(':')?))
+        }
+        None => input.split_once(':'),
+    }
+}
+
+/// Where to open a connection for a URL host and port.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Route {
+    pub host: String,
+    pub port: u16,
+    /// Set by --resolve or when `host` is an IP address, otherwise `host` needs a DNS lookup.
+    pub addresses: Option<Vec<IpAddr>>,
+}
+
+/// The --resolve and --connect-to mappings of a run.
+#[derive(Debug, Clone, Default)]
+pub struct Overrides {
+    pub resolve: Vec<Resolve>,
+    pub connect_to: Vec<ConnectTo>,
+}
+
+impl Overrides {
+    /// Applies the first matching --connect-to, then --resolve to the resulting host and port.
+    pub fn route(&self, host: &str, port: u16) -> Route {
+        let host = host.to_lowercase();
+
+        let (host, port) = match self.connect_to.iter().find(|connect_to| {
+            connect_to
+                .from_host
+                .as_ref()
+                .is_none_or(|from| *from == host)
+                && connect_to.from_port.is_none_or(|from| from == port)
+        }) {
+            Some(connect_to) => (
+                connect_to.to_host.clone().unwrap_or(host),
+                connect_to.to_port.unwrap_or(port),
+            ),
+            None => (host, port),
+        };
+
+        let addresses = match host.parse::<IpAddr>() {
+            Ok(ip) => Some(vec![ip]),
+            Err(_) => self
+                .resolve
+                .iter()
+                .find(|resolve| resolve.host == host && resolve.port == port)
+                .map(|resolve| resolve.addresses.clone()),
+        };
+
+        Route {
+            host,
+            port,
+            addresses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ConnectTo, Overrides, Resolve, Route};
+
+    #[test]
+    fn parses_mappings() {
+        let resolve = Resolve::parse("Example.com:443:10.0.0.1,[::1]").unwrap();
+        assert_eq!(resolve.host, "example.com");
+        assert_eq!(resolve.port, 443);
+        assert_eq!(
+            resolve.addresses,
+            ["10.0.0.1", "::1"].map(|ip| ip.parse::<std::net::IpAddr>().unwrap())
+        );
+
+        assert!(Resolve::parse("example.com:443").is_err());
+        assert!(Resolve::parse("example.com:https:10.0.0.1").is_err());
+        assert!(Resolve::parse("example.com:443:lb.internal").is_err());
+
+        assert_eq!(
+            ConnectTo::parse("::[::1]:8443").unwrap(),
+            ConnectTo {
+                from_host: None,
+                from_port: None,
+                to_host: Some("::1".to_string()),
+                to_port: Some(8443),
+            }
+        );
+        assert!(ConnectTo::parse("example.com:443:lb.internal").is_err());
+
+        assert_eq!(Resolve::parse("[::1]:443:10.0.0.1").unwrap().host, "::1");
+        assert_eq!(
+            ConnectTo::parse("[2001:db8::1]:443:lb.internal:8443").unwrap(),
+            ConnectTo {
+                from_host: Some("2001:db8::1".to_string()),
+                from_port: Some(443),
+                to_host: Some("lb.internal".to_string()),
+                to_port: Some(8443),
+            }
+        );
+        assert!(Resolve::parse("[::1:443:10.0.0.1").is_err());
+        assert!(Resolve::parse("[::1]443:10.0.0.1").is_err());
+    }
+
+    #[test]
+    fn routes_connections() {
+        let overrides = Overrides {
+            resolve: vec![Resolve::parse("lb-2.internal:8443:10.0.0.2,10.0.0.3").unwrap()],
+            connect_to: vec![
+                ConnectTo::parse("example.com:443:lb-2.internal:8443").unwrap(),
+                ConnectTo::parse("example.com::lb-1.internal:").unwrap(),
+            ],
+        };
+
+        let route = overrides.route("example.com", 443);
+        assert_eq!(route.host, "lb-2.internal");
+        assert_eq!(route.addresses.unwrap().len(), 2);
+
+        assert_eq!(
+            overrides.route("EXAMPLE.com", 80),
+            Route {
+                host: "lb-1.internal".to_string(),
+                port: 80,
+                addresses: None,
+            }
+        );
+        assert_eq!(overrides.route("other.com", 443).host, "other.com");
+        assert!(overrides.route("127.0.0.1", 80).addresses.is_some());
+    }
+}
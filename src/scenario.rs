@@ -0,0 +1,420 @@
+use super::*;
+use hyper::header::{HeaderName, HeaderValue};
+use json_path::JsonPath;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use template::Template;
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawScenario {
+    steps: Vec<RawStep>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RawStep {
+    name: Option<String>,
+    method: Option<config::Method>,
+    url: String,
+    #[serde(default)]
+    headers: BTreeMap<String, String>,
+    body: Option<String>,
+    form: Option<Vec<String>>,
+    #[serde(default)]
+    extract: BTreeMap<String, RawExtractor>,
+    #[serde(default)]
+    checks: checks::RawChecks,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RawExtractor {
+    Json(String),
+    Regex(String),
+    Header(String),
+}
+
+/// Where a step takes the value of a variable from.
+#[derive(Debug, Clone)]
+pub enum Extractor {
+    Json(JsonPath),
+    /// The first capture group, or the whole match when the regex has no groups.
+    Regex(regex::Regex),
+    Header(HeaderName),
+}
+
+impl Extractor {
+    /// The extracted value, `None` when the response doesn't contain it.
+    pub fn extract(&self, response: &client::Response) -> Option<String> {
+        match self {
+            Extractor::Json(path) => {
+                let json: Value = serde_json::from_slice(&response.body).ok()?;
+
+                match path.select(&json)? {
+                    Value::Null => None,
+                    Value::String(value) => Some(value.clone()),
+                    value => Some(value.to_string()),
+                }
+            }
+            Extractor::Regex(regex) => {
+                let text = String::from_utf8_lossy(&response.body);
+                let captures = regex.captures(&text)?;
+
+                captures
+                    .get(1)
+                    .or_else(|| captures.get(0))
+                    .map(|value| value.as_str().to_string())
+            }
+            Extractor::Header(name) => response
+                .headers
+                .get(name)?
+                .to_str()
+                .ok()
+                .map(|value| value.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Step {
+    pub name: String,
+    pub method: hyper::Method,
+    pub url: Template,
+    /// Sent on top of the --header values.
+    pub headers: Vec<(HeaderName, Template)>,
+    pub body: Option<body::RequestBody>,
+    /// Content-Type of `body`, sent unless the headers set one.
+    pub content_type: Option<HeaderValue>,
+    /// Index of the variable and how to extract its value.
+    pub extract: Vec<(usize, Extractor)>,
+    /// Run on top of the checks given on the command line.
+    pub checks: Vec<checks::Check>,
+}
+
+impl Step {
+    /// Templates may only use variables extracted by earlier steps, `parser` collects the
+    /// variables extracted by this one.
+    fn compile(index: usize, raw: RawStep, parser: &mut template::Parser) -> Result<Self, String> {
+        let name = raw.name.unwrap_or_else(|| format!("step {}", index + 1));
+        let in_step = |e: String| format!("{name}: {e}");
+
+        let url = parser.parse(&raw.url).map_err(in_step)?;
+        if url.is_static() {
+            Url::parse(&raw.url).map_err(|e| in_step(format!("{e} : {}", raw.url)))?;
+        }
+
+        let headers = raw
+            .headers
+            .iter()
+            .map(|(header, value)| {
+                let header = HeaderName::from_bytes(header.as_bytes())
+                    .map_err(|e| in_step(format!("{e} : {header}")))?;
+
+                Ok((header, parser.parse(value).map_err(in_step)?))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        let body = match (raw.body, raw.form) {
+            (Some(_), Some(_)) => {
+                return Err(in_step("Only one of body and form can be used".to_string()))
+            }
+            (Some(body), None) => Some(body::RequestBody::Text(
+                parser.parse(&body).map_err(in_step)?,
+            )),
+            (None, Some(fields)) => Some(body::parse_form(&fields, parser).map_err(in_step)?),
+            (None, None) => None,
+        };
+
+        let content_type = body
+            .as_ref()
+            .and_then(|body| body.content_type())
+            .map(|content_type| {
+                HeaderValue::from_str(&content_type)
+                    .map_err(|e| in_step(format!("{e} : {content_type}")))
+            })
+            .transpose()?;
+
+        let extract = raw
+            .extract
+            .into_iter()
+            .map(|(variable, extractor)| {
+                let extractor = match extractor {
+                    RawExtractor::Json(path) => Extractor::Json(JsonPath::parse(&path)?),
+                    RawExtractor::Regex(regex) => {
+                        Extractor::Regex(regex::Regex::new(&regex).map_err(|e| e.to_string())?)
+                    }
+                    RawExtractor::Header(header) => Extractor::Header(
+                        HeaderName::from_bytes(header.as_bytes())
+                            .map_err(|e| format!("{e} : {header}"))?,
+                    ),
+                };
+
+                let index = match parser.variables.iter().position(|v| *v == variable) {
+                    Some(index) => index,
+                    None => {
+                        parser.variables.push(variable);
+                        parser.variables.len() - 1
+                    }
+                };
+
+                Ok((index, extractor))
+            })
+            .collect::<Result<Vec<_>, String>>()
+            .map_err(in_step)?;
+
+        let checks = raw
+            .checks
+            .compile()
+            .map_err(in_step)?
+            .into_iter()
+            .map(|check| check.in_step(&name))
+            .collect();
+
+        Ok(Step {
+            method: raw.method.unwrap_or(config::Method::GET).into(),
+            checks,
+            name,
+            url,
+            headers,
+            body,
+            content_type,
+            extract,
+        })
+    }
+}
+
+/// Ordered steps run by every virtual user, passing extracted values on as `%{name}%` variables.
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    pub steps: Vec<Step>,
+    pub variables: Vec<String>,
+}
+
+impl Scenario {
+    /// Loads `[[steps]]` from a TOML file, or a YAML file ending in `.yaml` or `.yml`.
+    pub fn load(path: &str, columns: &[String]) -> Result<Self, String> {
+        config_file::read(path)
+            .and_then(|value| Scenario::from_value(value, columns))
+            .map_err(|e| format!("Invalid scenario file {path}: {e}"))
+    }
+
+    fn from_value(value: Value, columns: &[String]) -> Result<Self, String> {
+        let raw: RawScenario = serde_json::from_value(value).map_err(|e| e.to_string())?;
+
+        if raw.steps.is_empty() {
+            return Err("no steps found".to_string());
+        }
+
+        let mut parser = template::Parser {
+            enabled: true,
+            columns: columns.to_vec(),
+            variables: vec![],
+        };
+
+        let steps = raw
+            .steps
+            .into_iter()
+            .enumerate()
+            .map(|(index, step)| Step::compile(index, step, &mut parser))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Ok(Scenario {
+            steps,
+            variables: parser.variables,
+        })
+    }
+}
+
+/// Runs every step once for a virtual user, stopping at the first failed request or extraction.
+pub async fn execute(
+    context: Arc<process::ExecuteContext>,
+    worker: process::WorkerSlot,
+    scheduled_at: Option<Instant>,
+    stage: Option<usize>,
+    row: Option<Arc<Vec<String>>>,
+) {
+    let scenario = match context.scenario.as_ref() {
+        Some(scenario) => scenario,
+        None => return,
+    };
+
+    let mut render_context = template::RenderContext {
+        worker_id: worker.id,
+        row,
+        variables: vec![String::new(); scenario.variables.len()],
+        ..Default::default()
+    };
+
+    // Only the first step waited for the schedule.
+    let mut scheduled_at = scheduled_at;
+
+    for step in scenario.steps.iter() {
+        render_context.seq = context
+            .sequence
+            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+
+        let rendered = step.url.render_url(&render_context);
+        let url = match Url::parse(&rendered) {
+            Ok(url) => url,
+            Err(e) => {
+                error!("{} -> {}", rendered.red(), e.to_string().magenta());
+                return;
+            }
+        };
+
+        let mut headers = process::request_headers(&context, &render_context);
+
+        for (name, template) in step.headers.iter() {
+            match HeaderValue::from_str(&template.render(&render_context)) {
+                Ok(value) => {
+                    headers.insert(name.clone(), value);
+                }
+                Err(e) => error!("{name}: {}", e.to_string().red()),
+            }
+        }
+
+        let body = match step.body.as_ref() {
+            Some(body) => {
+                if let Some(content_type) = step.content_type.as_ref() {
+                    headers
+                        .entry(hyper::header::CONTENT_TYPE)
+                        .or_insert_with(|| content_type.clone());
+                }
+
+                body.encode(|template| template.render(&render_context))
+                    .into()
+            }
+            None => Default::default(),
+        };
+
+        let response = process::send(
+            &context,
+            worker.id,
+            client::Request {
+                method: step.method.clone(),
+                url,
+                headers,
+                body,
+            },
+            scheduled_at.take(),
+            stage,
+            context.grouping.as_ref().map(|_| step.name.clone()),
+            &step.checks,
+        )
+        .await;
+
+        let response = match response {
+            Some(response) => response,
+            None => return,
+        };
+
+        for (index, extractor) in step.extract.iter() {
+            match extractor.extract(&response) {
+                Some(value) => render_context.variables[*index] = value,
+                None => {
+                    error!(
+                        "{}: {} {}",
+                        step.name,
+                        "could not extract".red(),
+                        scenario.variables[*index].magenta()
+                    );
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Extractor, Scenario};
+    use crate::json_path::JsonPath;
+    use hyper::header::HeaderValue;
+
+    #[test]
+    fn extracts_from_responses() {
+        let mut headers = hyper::header::HeaderMap::new();
+        headers.insert("x-request-id", "abc".parse().unwrap());
+
+        let response = crate::client::Response {
+            headers,
+            body: r#"{"token": "t0k", "count": 3} id=42"#.into(),
+            ..Default::default()
+        };
+
+        let header = Extractor::Header("x-request-id".parse().unwrap());
+        assert_eq!(header.extract(&response).as_deref(), Some("abc"));
+
+        let regex = Extractor::Regex(regex::Regex::new("id=(\\d+)").unwrap());
+        assert_eq!(regex.extract(&response).as_deref(), Some("42"));
+
+        // The body isn't JSON as a whole.
+        let json = Extractor::Json(JsonPath::parse("$.token").unwrap());
+        assert_eq!(json.extract(&response), None);
+    }
+
+    #[test]
+    fn compiles_steps() {
+        let scenario = Scenario::from_value(
+            toml::from_str(
+                r#"
+[[steps]]
+name = "login"
+method = "post"
+url = "https://shop.example.com/login"
+form = ["user=%{user}%"]
+extract.token = { json = "$.token" }
+
+[[steps]]
+url = "https://shop.example.com/cart/%{token}%"
+headers = { Authorization = "Bearer %{token}%" }
+"#,
+            )
+            .unwrap(),
+            &["user".to_string()],
+        )
+        .unwrap();
+
+        assert_eq!(scenario.variables, vec!["token"]);
+        assert_eq!(scenario.steps[0].method, hyper::Method::POST);
+        assert_eq!(
+            scenario.steps[0].content_type,
+            Some(HeaderValue::from_static(
+                "application/x-www-form-urlencoded"
+            ))
+        );
+        assert_eq!(scenario.steps[1].name, "step 2");
+        assert_eq!(scenario.steps[1].content_type, None);
+        assert!(!scenario.steps[1].url.is_static());
+
+        // Extracted values can't change the path or query of the next step, headers get them raw.
+        let context = crate::template::RenderContext {
+            variables: vec!["a/b&c d".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            scenario.steps[1].url.render_url(&context),
+            "https://shop.example.com/cart/a%2Fb%26c%20d"
+        );
+        assert_eq!(
+            scenario.steps[1].headers[0].1.render(&context),
+            "Bearer a/b&c d"
+        );
+
+        // A step can't use what it extracts itself.
+        let own_variable = Scenario::from_value(
+            toml::from_str(
+                r#"
+[[steps]]
+url = "https://shop.example.com/%{id}%"
+extract.id = { header = "x-id" }
+"#,
+            )
+            .unwrap(),
+            &[],
+        );
+        assert!(own_variable.is_err());
+    }
+}
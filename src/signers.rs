@@ -0,0 +1,452 @@
+use super::*;
+use hmac::Mac;
+use hyper::header::{self, HeaderName, HeaderValue};
+use rand::Rng;
+use sha2::Digest as _;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+/// Adds authentication headers to requests once their templates have been rendered.
+pub trait Signer: std::fmt::Debug + Send + Sync {
+    fn sign(&self, request: &mut client::Request);
+
+    /// The headers `sign` sets, removed from redirected requests before they are signed again.
+    fn headers(&self) -> Vec<HeaderName>;
+
+    /// Called with 401 responses, returns true when the request should be signed and sent once more.
+    fn challenged(&self, _response: &client::Response) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum DigestAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl DigestAlgorithm {
+    fn hash(&self, input: &str) -> String {
+        match self {
+            DigestAlgorithm::Md5 => hex::encode(md5::Md5::digest(input.as_bytes())),
+            DigestAlgorithm::Sha256 => hex::encode(sha2::Sha256::digest(input.as_bytes())),
+        }
+    }
+}
+
+/// What the server asked for in its `WWW-Authenticate: Digest` header.
+#[derive(Debug, Clone, PartialEq)]
+struct Challenge {
+    realm: String,
+    nonce: String,
+    opaque: Option<String>,
+    algorithm: DigestAlgorithm,
+    /// `-sess` algorithms hash the client nonce into the credentials.
+    session: bool,
+    /// Only `auth` is supported, servers that don't offer it get the legacy RFC 2069 response.
+    qop: bool,
+}
+
+impl Challenge {
+    fn parse(header: &str) -> Option<Self> {
+        let (scheme, params) = header.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("digest") {
+            return None;
+        }
+
+        let params = auth_params(params);
+        let param = |name: &str| {
+            params
+                .iter()
+                .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.clone())
+        };
+
+        let algorithm = param("algorithm").unwrap_or_else(|| "MD5".to_string());
+        let (algorithm, session) = match algorithm.to_ascii_uppercase().as_str() {
+            "MD5" => (DigestAlgorithm::Md5, false),
+            "MD5-SESS" => (DigestAlgorithm::Md5, true),
+            "SHA-256" => (DigestAlgorithm::Sha256, false),
+            "SHA-256-SESS" => (DigestAlgorithm::Sha256, true),
+            _ => return None,
+        };
+
+        Some(Challenge {
+            realm: param("realm").unwrap_or_default(),
+            nonce: param("nonce")?,
+            opaque: param("opaque"),
+            algorithm,
+            session,
+            qop: param("qop").is_some_and(|qop| {
+                qop.split(',')
+                    .any(|qop| qop.trim().eq_ignore_ascii_case("auth"))
+            }),
+        })
+    }
+}
+
+/// Splits `key=value, key="quoted, value"` auth parameters.
+fn auth_params(input: &str) -> Vec<(String, String)> {
+    let mut params = vec![];
+    let mut rest = input.trim();
+
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_string();
+        let after = after.trim_start();
+
+        let (value, remaining) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let mut end = quoted.len();
+
+                while let Some((index, c)) = chars.next() {
+                    match c {
+                        '\\' => value.extend(chars.next().map(|(_, c)| c)),
+                        '"' => {
+                            end = index + 1;
+                            break;
+                        }
+                        c => value.push(c),
+                    }
+                }
+
+                (value, &quoted[end..])
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+
+        params.push((key, value));
+        rest = remaining.trim_start().trim_start_matches(',');
+    }
+
+    params
+}
+
+/// HTTP Digest authentication, see --digest-auth. The first request is sent unsigned to get a
+/// challenge, which is then shared by every virtual user until the server sends a new one.
+#[derive(Debug)]
+pub struct DigestAuth {
+    username: String,
+    password: String,
+    challenge: Mutex<Option<Challenge>>,
+    /// Requests signed with the current nonce.
+    nonce_count: AtomicU32,
+}
+
+impl DigestAuth {
+    pub fn new(username: &str, password: &str) -> Self {
+        DigestAuth {
+            username: username.to_string(),
+            password: password.to_string(),
+            challenge: Mutex::new(None),
+            nonce_count: AtomicU32::new(0),
+        }
+    }
+
+    fn authorization(
+        &self,
+        challenge: &Challenge,
+        method: &hyper::Method,
+        uri: &str,
+        nonce_count: u32,
+        cnonce: &str,
+    ) -> String {
+        let hash = |input: String| challenge.algorithm.hash(&input);
+        let nc = format!("{nonce_count:08x}");
+
+        let mut ha1 = hash(format!(
+            "{}:{}:{}",
+            self.username, challenge.realm, self.password
+        ));
+        if challenge.session {
+            ha1 = hash(format!("{ha1}:{}:{cnonce}", challenge.nonce));
+        }
+
+        let ha2 = hash(format!("{method}:{uri}"));
+
+        let response = match challenge.qop {
+            true => hash(format!(
+                "{ha1}:{}:{nc}:{cnonce}:auth:{ha2}",
+                challenge.nonce
+            )),
+            false => hash(format!("{ha1}:{}:{ha2}", challenge.nonce)),
+        };
+
+        let algorithm = match (challenge.algorithm, challenge.session) {
+            (DigestAlgorithm::Md5, false) => "MD5",
+            (DigestAlgorithm::Md5, true) => "MD5-sess",
+            (DigestAlgorithm::Sha256, false) => "SHA-256",
+            (DigestAlgorithm::Sha256, true) => "SHA-256-sess",
+        };
+
+        let mut header = format!(
+            r#"Digest username="{}", realm="{}", nonce="{}", uri="{uri}", algorithm={algorithm}, response="{response}""#,
+            quote(&self.username),
+            quote(&challenge.realm),
+            quote(&challenge.nonce),
+        );
+
+        if challenge.qop {
+            header.push_str(&format!(r#", qop=auth, nc={nc}, cnonce="{cnonce}""#));
+        }
+
+        if let Some(opaque) = challenge.opaque.as_ref() {
+            header.push_str(&format!(r#", opaque="{}""#, quote(opaque)));
+        }
+
+        header
+    }
+}
+
+impl Signer for DigestAuth {
+    fn sign(&self, request: &mut client::Request) {
+        let challenge = match self.challenge.lock().unwrap().clone() {
+            Some(challenge) => challenge,
+            None => return,
+        };
+
+        let nonce_count = self.nonce_count.fetch_add(1, Ordering::Relaxed) + 1;
+        let cnonce = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        let authorization = self.authorization(
+            &challenge,
+            &request.method,
+            &path_and_query(&request.url),
+            nonce_count,
+            &cnonce,
+        );
+
+        if let Ok(mut value) = HeaderValue::from_str(&authorization) {
+            value.set_sensitive(true);
+            request.headers.insert(header::AUTHORIZATION, value);
+        }
+    }
+
+    fn headers(&self) -> Vec<HeaderName> {
+        vec![header::AUTHORIZATION]
+    }
+
+    fn challenged(&self, response: &client::Response) -> bool {
+        let challenge = response
+            .headers
+            .get_all(header::WWW_AUTHENTICATE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .find_map(Challenge::parse);
+
+        match challenge {
+            Some(challenge) => {
+                let mut current = self.challenge.lock().unwrap();
+
+                if current.as_ref() != Some(&challenge) {
+                    *current = Some(challenge);
+                    self.nonce_count.store(0, Ordering::Relaxed);
+                }
+
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Header carrying the hex SHA-256 of the body of HMAC signed requests.
+const CONTENT_SHA256: &str = "x-content-sha256";
+
+/// Signs requests with HMAC-SHA256, see --hmac.
+///
+/// The signature covers `METHOD\npath?query\ndate\nhex(sha256(body))`. The `Date` header is added
+/// unless given, the body hash is sent in `X-Content-SHA256` and the signature in `header` as
+/// `HMAC-SHA256 KeyId=<key id>, Signature=<base64 signature>`.
+#[derive(Debug)]
+pub struct HmacSigner {
+    key_id: String,
+    secret: Vec<u8>,
+    header: HeaderName,
+}
+
+impl HmacSigner {
+    pub fn new(key_id: &str, secret: &str, header: HeaderName) -> Self {
+        HmacSigner {
+            key_id: key_id.to_string(),
+            secret: secret.as_bytes().to_vec(),
+            header,
+        }
+    }
+
+    fn signature(&self, string_to_sign: &str) -> String {
+        let mut mac = hmac::Hmac::<sha2::Sha256>::new_from_slice(&self.secret)
+            .expect("HMAC accepts keys of any size");
+        mac.update(string_to_sign.as_bytes());
+
+        base64::encode(mac.finalize().into_bytes())
+    }
+}
+
+impl Signer for HmacSigner {
+    fn sign(&self, request: &mut client::Request) {
+        let date = request
+            .headers
+            .entry(header::DATE)
+            .or_insert_with(|| {
+                HeaderValue::from_str(&httpdate::fmt_http_date(std::time::SystemTime::now()))
+                    .unwrap()
+            })
+            .to_str()
+            .unwrap_or_default()
+            .to_string();
+
+        let body_hash = hex::encode(sha2::Sha256::digest(&request.body));
+        let string_to_sign = format!(
+            "{}\n{}\n{date}\n{body_hash}",
+            request.method,
+            path_and_query(&request.url)
+        );
+
+        request.headers.insert(
+            HeaderName::from_static(CONTENT_SHA256),
+            HeaderValue::from_str(&body_hash).unwrap(),
+        );
+
+        let value = format!(
+            "HMAC-SHA256 KeyId={}, Signature={}",
+            self.key_id,
+            self.signature(&string_to_sign)
+        );
+
+        if let Ok(mut value) = HeaderValue::from_str(&value) {
+            value.set_sensitive(true);
+            request.headers.insert(self.header.clone(), value);
+        }
+    }
+
+    fn headers(&self) -> Vec<HeaderName> {
+        vec![self.header.clone(), HeaderName::from_static(CONTENT_SHA256)]
+    }
+}
+
+fn path_and_query(url: &Url) -> String {
+    match url.query() {
+        Some(query) => format!("{}?{query}", url.path()),
+        None => url.path().to_string(),
+    }
+}
+
+fn quote(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Challenge, DigestAlgorithm, DigestAuth, HmacSigner, Signer};
+    use hyper::header::{self, HeaderMap};
+
+    #[test]
+    fn digest_responses() {
+        // RFC 2617, section 3.5.
+        let challenge = Challenge::parse(
+            r#"Digest realm="testrealm@host.com", qop="auth,auth-int", nonce="dcd98b7102dd2f0e8b11d0f600bfb0c093", opaque="5ccc069c403ebaf9f0171e9517f40e41""#,
+        )
+        .unwrap();
+        assert_eq!(challenge.algorithm, DigestAlgorithm::Md5);
+        assert!(challenge.qop);
+
+        let digest = DigestAuth::new("Mufasa", "Circle Of Life");
+        let authorization = digest.authorization(
+            &challenge,
+            &hyper::Method::GET,
+            "/dir/index.html",
+            1,
+            "0a4f113b",
+        );
+        assert!(authorization.contains(r#"response="6629fae49393a05397450978507c4ef1""#));
+        assert!(authorization.contains(r#"opaque="5ccc069c403ebaf9f0171e9517f40e41""#));
+
+        // RFC 7616, section 3.9.1.
+        let challenge = Challenge::parse(
+            r#"Digest realm="http-auth@example.org", qop="auth, auth-int", algorithm=SHA-256, nonce="7ypf/xlj9XXwfDPEoM4URrv/xwf94BcCAzFZH4GiTo0v", opaque="FQhe/qaU925kfnzjCev0ciny7QMkPqMAFRtzCUYo5tdS""#,
+        )
+        .unwrap();
+        let digest = DigestAuth::new("Mufasa", "Circle of Life");
+        let authorization = digest.authorization(
+            &challenge,
+            &hyper::Method::GET,
+            "/dir/index.html",
+            1,
+            "f2/wE4q74E6zIJEtWaHKaf5wv/H5QzzpXusqGemxURZJ",
+        );
+        assert!(authorization.contains(
+            r#"response="753927fa0e85d155564e2e272a28d1802ca10daf4496794697cf8db5856cb6c1""#
+        ));
+
+        assert!(Challenge::parse(r#"Basic realm="x""#).is_none());
+    }
+
+    #[test]
+    fn digest_answers_challenges() {
+        let digest = DigestAuth::new("user", "pass");
+        let mut request = crate::client::Request {
+            method: hyper::Method::GET,
+            url: "http://example.com/a?b=1".parse().unwrap(),
+            headers: HeaderMap::new(),
+            body: Default::default(),
+        };
+
+        digest.sign(&mut request);
+        assert!(request.headers.is_empty());
+
+        let mut headers = HeaderMap::new();
+        headers.append(
+            header::WWW_AUTHENTICATE,
+            "Basic realm=\"x\"".parse().unwrap(),
+        );
+        headers.append(
+            header::WWW_AUTHENTICATE,
+            "Digest realm=\"x\", nonce=\"abc\", qop=\"auth\""
+                .parse()
+                .unwrap(),
+        );
+        let response = crate::client::Response {
+            status: hyper::StatusCode::UNAUTHORIZED,
+            headers,
+            ..Default::default()
+        };
+
+        assert!(digest.challenged(&response));
+        digest.sign(&mut request);
+
+        let authorization = request.headers[header::AUTHORIZATION].to_str().unwrap();
+        assert!(authorization
+            .starts_with(r#"Digest username="user", realm="x", nonce="abc", uri="/a?b=1""#));
+        assert!(authorization.contains("nc=00000001"));
+    }
+
+    #[test]
+    fn hmac_signatures() {
+        let signer = HmacSigner::new("key-1", "secret", header::AUTHORIZATION);
+        let mut request = crate::client::Request {
+            method: hyper::Method::POST,
+            url: "https://api.example.com/orders?page=2".parse().unwrap(),
+            headers: HeaderMap::new(),
+            body: "{\"id\": 1}".into(),
+        };
+        request.headers.insert(
+            header::DATE,
+            "Tue, 15 Nov 1994 08:12:31 GMT".parse().unwrap(),
+        );
+
+        signer.sign(&mut request);
+
+        assert_eq!(
+            request.headers["x-content-sha256"],
+            "354aaef7a5f6ecbb2faee49fbe47a24e024cb62b3183b853a1ecc01e01920e49"
+        );
+        assert_eq!(
+            request.headers[header::AUTHORIZATION],
+            "HMAC-SHA256 KeyId=key-1, Signature=NNnfm0WF12F93+URzfKDS9BI09NpdEOxWdzedUy0NTY="
+        );
+    }
+}
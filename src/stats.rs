@@ -0,0 +1,430 @@
+use super::*;
+use hdrhistogram::Histogram;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Upper bound for tracked latencies, one hour in microseconds.
+pub const MAX_TRACKED_LATENCY_US: u64 = 60 * 60 * 1_000_000;
+
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // Each runtime thread records into its own shard so tasks rarely contend on a lock.
+    static SHARD: usize = NEXT_SHARD.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Latency histogram in microseconds. It starts small and grows with the largest recorded value.
+pub fn latency_histogram() -> Histogram<u64> {
+    Histogram::new(3).unwrap()
+}
+
+/// Lower precision histogram (within 1%) for per-URL groups, which can be numerous.
+pub fn group_latency_histogram() -> Histogram<u64> {
+    Histogram::new(2).unwrap()
+}
+
+pub fn record_latency(histogram: &mut Histogram<u64>, duration: Duration) {
+    let value = (duration.as_micros() as u64).min(MAX_TRACKED_LATENCY_US);
+
+    // `record` resizes the histogram as needed, `saturating_record` would clamp to its current range.
+    if histogram.record(value).is_err() {
+        histogram.saturating_record(value);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorKind {
+    Timeout,
+    Connect,
+    Redirect,
+    Body,
+    Decode,
+    Request,
+}
+
+impl ErrorKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorKind::Timeout => "timeout",
+            ErrorKind::Connect => "connect",
+            ErrorKind::Redirect => "redirect",
+            ErrorKind::Body => "body",
+            ErrorKind::Decode => "decode",
+            ErrorKind::Request => "request",
+        }
+    }
+}
+
+pub fn version_name(version: hyper::Version) -> &'static str {
+    match version {
+        hyper::Version::HTTP_09 => "HTTP/0.9",
+        hyper::Version::HTTP_10 => "HTTP/1.0",
+        hyper::Version::HTTP_11 => "HTTP/1.1",
+        hyper::Version::HTTP_2 => "HTTP/2",
+        hyper::Version::HTTP_3 => "HTTP/3",
+        _ => "unknown",
+    }
+}
+
+/// Constant-size aggregate of any number of results.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub responses: u64,
+    pub errors: BTreeMap<ErrorKind, u64>,
+    pub status_codes: HashMap<u16, u64>,
+    /// Time from sending the request until the response headers arrived.
+    pub latency: Histogram<u64>,
+    /// Same as `latency` but measured from the scheduled send time, only recorded in --rate mode.
+    pub scheduled_latency: Histogram<u64>,
+    pub body_length: u64,
+    /// Responses that failed at least one check.
+    pub failed_checks: u64,
+    /// Failures of each check by name.
+    pub checks: BTreeMap<String, u64>,
+    /// Responses by HTTP version, see `version_name`.
+    pub protocols: BTreeMap<&'static str, u64>,
+    /// Requests, redirects included, sent on a connection opened earlier. Opened connections
+    /// are counted by the connector, see `connector::ConnectionCounts`.
+    pub connections_reused: u64,
+    /// Responses that needed a second round trip after a 401, see `process::HttpResponse`.
+    pub retried: u64,
+}
+
+impl Default for Summary {
+    fn default() -> Self {
+        Summary {
+            responses: 0,
+            errors: BTreeMap::new(),
+            status_codes: HashMap::new(),
+            latency: latency_histogram(),
+            scheduled_latency: latency_histogram(),
+            body_length: 0,
+            failed_checks: 0,
+            checks: BTreeMap::new(),
+            protocols: BTreeMap::new(),
+            connections_reused: 0,
+            retried: 0,
+        }
+    }
+}
+
+impl Summary {
+    fn for_group() -> Self {
+        Summary {
+            latency: group_latency_histogram(),
+            scheduled_latency: group_latency_histogram(),
+            ..Default::default()
+        }
+    }
+
+    pub fn record(&mut self, result: &process::HttpResult) {
+        match result {
+            process::HttpResult::Response(val) => {
+                self.responses += 1;
+                *self.status_codes.entry(val.status.as_u16()).or_insert(0) += 1;
+                self.body_length += val.length as u64;
+                *self.protocols.entry(version_name(val.version)).or_insert(0) += 1;
+                self.connections_reused += val.connections_reused as u64;
+                self.retried += val.retried as u64;
+
+                record_latency(&mut self.latency, val.duration);
+
+                if let Some(scheduled_duration) = val.scheduled_duration {
+                    record_latency(&mut self.scheduled_latency, scheduled_duration);
+                }
+
+                if !val.failed_checks.is_empty() {
+                    self.failed_checks += 1;
+                }
+
+                for check in val.failed_checks.iter() {
+                    match self.checks.get_mut(check) {
+                        Some(count) => *count += 1,
+                        None => {
+                            self.checks.insert(check.clone(), 1);
+                        }
+                    }
+                }
+            }
+            process::HttpResult::Error(err) => {
+                *self.errors.entry(err.kind()).or_insert(0) += 1;
+            }
+        }
+    }
+
+    pub fn merge(&mut self, other: &Summary) {
+        self.responses += other.responses;
+        self.body_length += other.body_length;
+        self.failed_checks += other.failed_checks;
+        self.connections_reused += other.connections_reused;
+        self.retried += other.retried;
+
+        for (protocol, count) in other.protocols.iter() {
+            *self.protocols.entry(protocol).or_insert(0) += count;
+        }
+
+        for (check, count) in other.checks.iter() {
+            *self.checks.entry(check.clone()).or_insert(0) += count;
+        }
+
+        for (kind, count) in other.errors.iter() {
+            *self.errors.entry(*kind).or_insert(0) += count;
+        }
+
+        for (status, count) in other.status_codes.iter() {
+            *self.status_codes.entry(*status).or_insert(0) += count;
+        }
+
+        self.latency.add(&other.latency).unwrap();
+        self.scheduled_latency
+            .add(&other.scheduled_latency)
+            .unwrap();
+    }
+
+    pub fn error_count(&self) -> u64 {
+        self.errors.values().sum()
+    }
+
+    pub fn total(&self) -> u64 {
+        self.responses + self.error_count()
+    }
+}
+
+/// Latency histograms for each phase of a request, see `client::Timings`.
+#[derive(Debug, Clone)]
+pub struct Phases {
+    pub dns: Histogram<u64>,
+    pub connect: Histogram<u64>,
+    pub tls: Histogram<u64>,
+    pub ttfb: Histogram<u64>,
+    pub download: Histogram<u64>,
+}
+
+impl Default for Phases {
+    fn default() -> Self {
+        Phases {
+            dns: latency_histogram(),
+            connect: latency_histogram(),
+            tls: latency_histogram(),
+            ttfb: latency_histogram(),
+            download: latency_histogram(),
+        }
+    }
+}
+
+impl Phases {
+    fn record(&mut self, timings: &client::Timings) {
+        let phases = [
+            (&mut self.dns, timings.dns),
+            (&mut self.connect, timings.connect),
+            (&mut self.tls, timings.tls),
+            (&mut self.ttfb, Some(timings.ttfb)),
+            (&mut self.download, Some(timings.download)),
+        ];
+
+        for (histogram, duration) in phases {
+            if let Some(duration) = duration {
+                record_latency(histogram, duration);
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &Phases) {
+        for ((_, histogram), (_, other)) in self.iter_mut().into_iter().zip(other.iter()) {
+            histogram.add(other).unwrap();
+        }
+    }
+
+    /// Phases in request order along with their names.
+    pub fn iter(&self) -> [(&'static str, &Histogram<u64>); 5] {
+        [
+            ("dns", &self.dns),
+            ("connect", &self.connect),
+            ("tls", &self.tls),
+            ("ttfb", &self.ttfb),
+            ("download", &self.download),
+        ]
+    }
+
+    fn iter_mut(&mut self) -> [(&'static str, &mut Histogram<u64>); 5] {
+        [
+            ("dns", &mut self.dns),
+            ("connect", &mut self.connect),
+            ("tls", &mut self.tls),
+            ("ttfb", &mut self.ttfb),
+            ("download", &mut self.download),
+        ]
+    }
+}
+
+/// Merged statistics for a whole run.
+#[derive(Debug, Clone, Default)]
+pub struct RunStats {
+    pub total: Summary,
+    /// One summary per --stages stage, indexed like the configured stages.
+    pub stages: Vec<Summary>,
+    /// One summary per URL or --group pattern, only filled when grouping is enabled.
+    pub groups: HashMap<String, Summary>,
+    /// Request phases of every response, DNS, connect and TLS only for new connections.
+    pub phases: Phases,
+}
+
+impl RunStats {
+    fn record(&mut self, stage: Option<usize>, group: Option<&str>, result: &process::HttpResult) {
+        self.total.record(result);
+
+        if let process::HttpResult::Response(val) = result {
+            self.phases.record(&val.timings);
+        }
+
+        if let Some(group) = group {
+            match self.groups.get_mut(group) {
+                Some(summary) => summary.record(result),
+                None => {
+                    let mut summary = Summary::for_group();
+                    summary.record(result);
+                    self.groups.insert(group.to_string(), summary);
+                }
+            }
+        }
+
+        if let Some(stage) = stage {
+            if self.stages.len() <= stage {
+                self.stages.resize_with(stage + 1, Summary::default);
+            }
+
+            self.stages[stage].record(result);
+        }
+    }
+
+    fn merge(&mut self, other: &RunStats) {
+        self.total.merge(&other.total);
+        self.phases.merge(&other.phases);
+
+        if self.stages.len() < other.stages.len() {
+            self.stages
+                .resize_with(other.stages.len(), Summary::default);
+        }
+
+        for (stage, summary) in other.stages.iter().enumerate() {
+            self.stages[stage].merge(summary);
+        }
+
+        for (group, summary) in other.groups.iter() {
+            self.groups
+                .entry(group.clone())
+                .or_insert_with(Summary::for_group)
+                .merge(summary);
+        }
+    }
+}
+
+/// Streaming aggregator shared by all request tasks, sharded per runtime thread.
+pub struct Recorder {
+    shards: Vec<Mutex<RunStats>>,
+}
+
+impl Recorder {
+    pub fn new(shards: usize) -> Self {
+        Recorder {
+            shards: (0..shards.max(1))
+                .map(|_| Mutex::new(RunStats::default()))
+                .collect(),
+        }
+    }
+
+    pub fn record(&self, stage: Option<usize>, group: Option<&str>, result: &process::HttpResult) {
+        let shard = SHARD.with(|shard| *shard) % self.shards.len();
+        self.shards[shard]
+            .lock()
+            .unwrap()
+            .record(stage, group, result);
+    }
+
+    pub fn snapshot(&self) -> RunStats {
+        let mut stats = RunStats::default();
+
+        for shard in self.shards.iter() {
+            stats.merge(&shard.lock().unwrap());
+        }
+
+        stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::process::{HttpResponse, HttpResult};
+    use std::time::Duration;
+
+    fn response(status: u16, millis: u64) -> HttpResult {
+        checked_response(status, millis, vec![])
+    }
+
+    fn checked_response(status: u16, millis: u64, failed_checks: Vec<String>) -> HttpResult {
+        HttpResult::Response(HttpResponse {
+            status: hyper::StatusCode::from_u16(status).unwrap(),
+            duration: Duration::from_millis(millis),
+            scheduled_duration: None,
+            length: 10,
+            timings: crate::client::Timings {
+                connect: Some(Duration::from_millis(1)),
+                ttfb: Duration::from_millis(millis - 1),
+                ..Default::default()
+            },
+            version: hyper::Version::HTTP_11,
+            connections_reused: 2,
+            retried: false,
+            failed_checks,
+        })
+    }
+
+    #[test]
+    fn recorder_merges_shards_and_stages() {
+        let recorder = super::Recorder::new(4);
+
+        recorder.record(Some(0), Some("/a"), &response(200, 10));
+        recorder.record(Some(1), Some("/b"), &response(200, 20));
+        recorder.record(Some(1), Some("/a"), &response(500, 30));
+        recorder.record(None, None, &response(200, 40));
+        recorder.record(
+            None,
+            Some("/b"),
+            &checked_response(200, 50, vec!["a".to_string(), "b".to_string()]),
+        );
+        recorder.record(
+            None,
+            None,
+            &checked_response(200, 60, vec!["a".to_string()]),
+        );
+
+        let stats = recorder.snapshot();
+
+        assert_eq!(stats.total.total(), 6);
+        assert_eq!(stats.total.body_length, 60);
+        assert_eq!(stats.total.status_codes.get(&200), Some(&5));
+        assert_eq!(stats.total.status_codes.get(&500), Some(&1));
+        assert_eq!(stats.total.latency.len(), 6);
+        assert_eq!(stats.total.failed_checks, 2);
+        assert_eq!(stats.total.checks.get("a"), Some(&2));
+        assert_eq!(stats.total.checks.get("b"), Some(&1));
+        assert_eq!(stats.total.scheduled_latency.len(), 0);
+        assert_eq!(stats.total.protocols.get("HTTP/1.1"), Some(&6));
+        assert_eq!(stats.total.connections_reused, 12);
+
+        assert_eq!(stats.stages.len(), 2);
+        assert_eq!(stats.stages[0].responses, 1);
+        assert_eq!(stats.stages[1].responses, 2);
+        assert!(stats.stages[1].latency.max() >= 30_000);
+
+        assert_eq!(stats.groups.len(), 2);
+        assert_eq!(stats.groups["/a"].responses, 2);
+        assert_eq!(stats.groups["/a"].status_codes.get(&500), Some(&1));
+        assert_eq!(stats.groups["/b"].responses, 2);
+        assert_eq!(stats.groups["/b"].failed_checks, 1);
+
+        assert_eq!(stats.phases.connect.len(), 6);
+        assert_eq!(stats.phases.dns.len(), 0);
+        assert!(stats.phases.ttfb.max() >= 39_000);
+    }
+}
@@ -0,0 +1,328 @@
+use super::*;
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use rand::distributions::Alphanumeric;
+use rand::prelude::*;
+
+/// Data column and variable values rendered into URLs keep only the unreserved characters as is.
+const URL_VALUE: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Literal(String),
+    /// `%RAND(min,max)%`, a number in min..max.
+    Rand {
+        min: u64,
+        max: u64,
+    },
+    Uuid,
+    Seq,
+    /// Unix time in seconds.
+    Timestamp,
+    NowMs,
+    RandStr(usize),
+    Choice(Vec<String>),
+    WorkerId,
+    /// `%{name}%`, index of a --data-file column.
+    Column(usize),
+    /// `%{name}%`, index of a value extracted by an earlier scenario step.
+    Variable(usize),
+}
+
+/// Values shared by every template rendered for the same request.
+#[derive(Debug, Default)]
+pub struct RenderContext {
+    pub worker_id: usize,
+    /// Global request counter, the same for the URL, headers and body of a request.
+    pub seq: u64,
+    /// --data-file row assigned to the request.
+    pub row: Option<Arc<Vec<String>>>,
+    /// Values extracted by the scenario steps run so far, empty until extracted.
+    pub variables: Vec<String>,
+}
+
+/// Parses templates when templating is enabled, knowing which --data-file columns and scenario
+/// variables exist.
+#[derive(Debug, Clone, Default)]
+pub struct Parser {
+    pub enabled: bool,
+    pub columns: Vec<String>,
+    pub variables: Vec<String>,
+}
+
+impl Parser {
+    /// Parses placeholders when enabled, otherwise the input is taken literally.
+    pub fn parse(&self, input: &str) -> Result<Template, String> {
+        match self.enabled {
+            true => Template::parse(input, &self.columns, &self.variables),
+            false => Ok(Template::literal(input)),
+        }
+    }
+}
+
+/// A string with placeholders, parsed once so rendering is a walk over its segments.
+/// `%ENV(NAME)%` is resolved while parsing.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Template {
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn literal(input: &str) -> Self {
+        Template {
+            segments: vec![Segment::Literal(input.to_string())],
+        }
+    }
+
+    /// Unknown `%...%` sequences are kept as is, so percent-encoded URLs are left alone.
+    /// `%{name}%` refers to a data column, or to a variable when no column has that name.
+    pub fn parse(input: &str, columns: &[String], variables: &[String]) -> Result<Self, String> {
+        let placeholder = regex::Regex::new(
+            "(?i)%(?:(RAND|UUID|SEQ|TIMESTAMP|NOW_MS|RANDSTR|CHOICE|ENV|WORKER_ID)(?:\\(([^)]*)\\))?|\\{([^}]*)\\})%",
+        )
+        .unwrap();
+
+        let mut segments = vec![];
+        let mut last = 0;
+
+        for c in placeholder.captures_iter(input) {
+            let matched = c.get(0).unwrap();
+            push_literal(&mut segments, &input[last..matched.start()]);
+            last = matched.end();
+
+            if let Some(name) = c.get(3) {
+                let position = |names: &[String]| names.iter().position(|n| n == name.as_str());
+
+                match (position(columns), position(variables)) {
+                    (Some(index), _) => segments.push(Segment::Column(index)),
+                    (None, Some(index)) => segments.push(Segment::Variable(index)),
+                    (None, None) => {
+                        return Err(format!(
+                            "Unknown data column or variable in template: {}",
+                            matched.as_str()
+                        ))
+                    }
+                }
+
+                continue;
+            }
+
+            let name = c[1].to_uppercase();
+            let args = c.get(2).map(|args| args.as_str());
+            let invalid = || format!("Invalid template placeholder: {}", matched.as_str());
+
+            let segment = match (name.as_str(), args) {
+                ("RAND", Some(args)) => {
+                    let (min, max) = args.split_once(',').ok_or_else(invalid)?;
+                    let min = min.trim().parse::<u64>().map_err(|_| invalid())?;
+                    let max = max.trim().parse::<u64>().map_err(|_| invalid())?;
+
+                    if min >= max {
+                        return Err(invalid());
+                    }
+
+                    Segment::Rand { min, max }
+                }
+                ("RANDSTR", Some(len)) => match len.trim().parse::<usize>() {
+                    Ok(len) if len > 0 => Segment::RandStr(len),
+                    _ => return Err(invalid()),
+                },
+                ("CHOICE", Some(choices)) => {
+                    Segment::Choice(choices.split(',').map(|c| c.to_string()).collect())
+                }
+                ("ENV", Some(name)) => match std::env::var(name) {
+                    Ok(value) => {
+                        push_literal(&mut segments, &value);
+                        continue;
+                    }
+                    Err(_) => return Err(format!("Environment variable {name} is not set")),
+                },
+                ("UUID", None) => Segment::Uuid,
+                ("SEQ", None) => Segment::Seq,
+                ("TIMESTAMP", None) => Segment::Timestamp,
+                ("NOW_MS", None) => Segment::NowMs,
+                ("WORKER_ID", None) => Segment::WorkerId,
+                _ => return Err(invalid()),
+            };
+
+            segments.push(segment);
+        }
+
+        push_literal(&mut segments, &input[last..]);
+
+        Ok(Template { segments })
+    }
+
+    /// True when rendering always yields the same string.
+    pub fn is_static(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| matches!(segment, Segment::Literal(_)))
+    }
+
+    pub fn render(&self, context: &RenderContext) -> String {
+        self.render_with(context, false)
+    }
+
+    /// Renders a URL, percent-encoding data column and variable values so they can't change its
+    /// structure. Headers and bodies get the values raw with `render`.
+    pub fn render_url(&self, context: &RenderContext) -> String {
+        self.render_with(context, true)
+    }
+
+    fn render_with(&self, context: &RenderContext, encode_values: bool) -> String {
+        let push_value = |output: &mut String, value: &str| match encode_values {
+            true => output.extend(utf8_percent_encode(value, URL_VALUE)),
+            false => output.push_str(value),
+        };
+
+        let mut rng = rand::thread_rng();
+        let mut output = String::new();
+
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Literal(text) => output.push_str(text),
+                Segment::Rand { min, max } => {
+                    output.push_str(&rng.gen_range(*min..*max).to_string())
+                }
+                Segment::Uuid => output.push_str(&uuid_v4(&mut rng)),
+                Segment::Seq => output.push_str(&context.seq.to_string()),
+                Segment::Timestamp => output.push_str(&unix_time().as_secs().to_string()),
+                Segment::NowMs => output.push_str(&unix_time().as_millis().to_string()),
+                Segment::RandStr(len) => output.extend(
+                    (&mut rng)
+                        .sample_iter(Alphanumeric)
+                        .take(*len)
+                        .map(char::from),
+                ),
+                Segment::Choice(choices) => output.push_str(choices.choose(&mut rng).unwrap()),
+                Segment::WorkerId => output.push_str(&context.worker_id.to_string()),
+                Segment::Column(index) => {
+                    if let Some(value) = context.row.as_ref().and_then(|row| row.get(*index)) {
+                        push_value(&mut output, value);
+                    }
+                }
+                Segment::Variable(index) => {
+                    if let Some(value) = context.variables.get(*index) {
+                        push_value(&mut output, value);
+                    }
+                }
+            }
+        }
+
+        output
+    }
+}
+
+fn push_literal(segments: &mut Vec<Segment>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+
+    match segments.last_mut() {
+        Some(Segment::Literal(last)) => last.push_str(text),
+        _ => segments.push(Segment::Literal(text.to_string())),
+    }
+}
+
+fn unix_time() -> Duration {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// Random (version 4) UUID in its hyphenated form.
+fn uuid_v4(rng: &mut impl Rng) -> String {
+    let mut bytes: [u8; 16] = rng.gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex: String = bytes.iter().map(|b| format!("{b:02x}")).collect();
+
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[0..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..32]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Parser, RenderContext, Template};
+    use std::sync::Arc;
+
+    #[test]
+    fn parses_and_renders_placeholders() {
+        std::env::set_var("VERDUN_TEMPLATE_TEST", "from-env");
+
+        let columns = vec!["id".to_string(), "token".to_string()];
+
+        let template = Template::parse(
+            "/item/%RAND(5,6)%?seq=%SEQ%&w=%worker_id%&e=%ENV(VERDUN_TEMPLATE_TEST)%&c=%CHOICE(a)%&x=%20&t=%{token}%",
+            &columns,
+            &[],
+        )
+        .unwrap();
+        assert!(!template.is_static());
+
+        let rendered = template.render(&RenderContext {
+            worker_id: 3,
+            seq: 42,
+            row: Some(Arc::new(vec!["1".to_string(), "secret".to_string()])),
+            variables: vec![],
+        });
+        assert_eq!(rendered, "/item/5?seq=42&w=3&e=from-env&c=a&x=%20&t=secret");
+
+        let variables = vec!["token".to_string(), "cart".to_string()];
+        let template = Template::parse("%{token}%/%{cart}%", &[], &variables).unwrap();
+        let rendered = template.render(&RenderContext {
+            variables: vec!["abc".to_string(), "7".to_string()],
+            ..Default::default()
+        });
+        assert_eq!(rendered, "abc/7");
+
+        let template =
+            Template::parse("https://example.com/%{id}%?q=%{token}%", &columns, &[]).unwrap();
+        let context = RenderContext {
+            row: Some(Arc::new(vec!["a/b".to_string(), "x&y=1 #z".to_string()])),
+            ..Default::default()
+        };
+        assert_eq!(
+            template.render_url(&context),
+            "https://example.com/a%2Fb?q=x%26y%3D1%20%23z"
+        );
+        assert_eq!(
+            template.render(&context),
+            "https://example.com/a/b?q=x&y=1 #z"
+        );
+
+        let random = Template::parse("%UUID% %RANDSTR(8)% %NOW_MS%", &[], &[])
+            .unwrap()
+            .render(&RenderContext::default());
+        let parts: Vec<_> = random.split(' ').collect();
+        assert_eq!(parts[0].len(), 36);
+        assert_eq!(&parts[0][14..15], "4");
+        assert_eq!(parts[1].len(), 8);
+        assert!(parts[2].parse::<u64>().is_ok());
+
+        assert!(Template::parse("%ENV(VERDUN_TEMPLATE_TEST)%", &[], &[])
+            .unwrap()
+            .is_static());
+        assert!(Template::parse("100%", &[], &[]).unwrap().is_static());
+        assert!(Template::parse("%RAND(9,1)%", &[], &[]).is_err());
+        assert!(Template::parse("%RANDSTR(x)%", &[], &[]).is_err());
+        assert!(Template::parse("%UUID(1)%", &[], &[]).is_err());
+        assert!(Template::parse("%ENV(VERDUN_TEMPLATE_UNSET)%", &[], &[]).is_err());
+        assert!(Template::parse("%{missing}%", &columns, &[]).is_err());
+        assert_eq!(
+            Parser::default().parse("%SEQ%").unwrap(),
+            Template::literal("%SEQ%")
+        );
+    }
+}
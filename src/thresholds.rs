@@ -0,0 +1,297 @@
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Metric {
+    /// Response time percentile, ex: 95 for `p95`.
+    Percentile(f64),
+    Min,
+    Max,
+    Mean,
+    /// Percentage of requests that failed without an HTTP response. Error statuses are responses,
+    /// they count in `CheckFailureRate` when checked with --expect-status.
+    TransportErrorRate,
+    /// Percentage of responses that failed at least one check.
+    CheckFailureRate,
+    Rps,
+    Requests,
+    Errors,
+}
+
+impl Metric {
+    fn parse(input: &str) -> Option<Self> {
+        match input {
+            "min" => Some(Metric::Min),
+            "max" => Some(Metric::Max),
+            "mean" | "avg" => Some(Metric::Mean),
+            "transport_error_rate" => Some(Metric::TransportErrorRate),
+            "check_failure_rate" => Some(Metric::CheckFailureRate),
+            "rps" => Some(Metric::Rps),
+            "requests" => Some(Metric::Requests),
+            "errors" => Some(Metric::Errors),
+            _ => {
+                let percentile = input.strip_prefix('p')?.parse::<f64>().ok()?;
+
+                match percentile > 0.0 && percentile <= 100.0 {
+                    true => Some(Metric::Percentile(percentile)),
+                    false => None,
+                }
+            }
+        }
+    }
+
+    fn is_latency(&self) -> bool {
+        matches!(
+            self,
+            Metric::Percentile(_) | Metric::Min | Metric::Max | Metric::Mean
+        )
+    }
+
+    fn is_rate(&self) -> bool {
+        matches!(self, Metric::TransportErrorRate | Metric::CheckFailureRate)
+    }
+
+    /// Latencies are in microseconds and rates in percent. `None` when there is nothing to
+    /// measure, such as latencies of a run without responses.
+    fn measure(&self, summary: &stats::Summary, elapsed: Duration) -> Option<f64> {
+        let latency = &summary.latency;
+
+        if self.is_latency() && latency.is_empty() {
+            return None;
+        }
+
+        Some(match self {
+            Metric::Percentile(percentile) => latency.value_at_quantile(percentile / 100.0) as f64,
+            Metric::Min => latency.min() as f64,
+            Metric::Max => latency.max() as f64,
+            Metric::Mean => latency.mean(),
+            Metric::TransportErrorRate => report::error_rate(summary),
+            Metric::CheckFailureRate => {
+                report::percentage(summary.failed_checks, summary.responses)
+            }
+            Metric::Rps => summary.responses as f64 / elapsed.as_secs_f64(),
+            Metric::Requests => summary.total() as f64,
+            Metric::Errors => summary.error_count() as f64,
+        })
+    }
+
+    fn format(&self, value: f64) -> String {
+        match self {
+            _ if self.is_latency() => report::format_micros_f64(value),
+            _ if self.is_rate() => format!("{value:.2}%"),
+            Metric::Rps => format!("{value:.2}"),
+            _ => format!("{value}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Operator {
+    fn holds(&self, actual: f64, limit: f64) -> bool {
+        match self {
+            Operator::Lt => actual < limit,
+            Operator::Le => actual <= limit,
+            Operator::Gt => actual > limit,
+            Operator::Ge => actual >= limit,
+        }
+    }
+}
+
+/// A --threshold such as `p95<300ms`, `transport_error_rate<1%` or `rps>200`, checked after the run.
+#[derive(Debug, Clone)]
+pub struct Threshold {
+    pub expression: String,
+    metric: Metric,
+    operator: Operator,
+    /// In the unit of the metric, see `Metric::measure`.
+    limit: f64,
+}
+
+/// Outcome of a threshold for a finished run.
+#[derive(Debug, Clone)]
+pub struct Evaluation<'a> {
+    pub threshold: &'a Threshold,
+    /// The measured value, formatted. `None` when it couldn't be measured, which fails the threshold.
+    pub actual: Option<String>,
+    pub passed: bool,
+}
+
+impl Threshold {
+    pub fn parse(expression: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!("Invalid threshold, should be metric<value or metric>value (ex: p95<300ms): {expression}")
+        };
+
+        let compact: String = expression.split_whitespace().collect();
+        let position = compact.find(['<', '>']).ok_or_else(invalid)?;
+        let (metric, rest) = compact.split_at(position);
+
+        let (operator, value) = match (rest.strip_prefix('<'), rest.strip_prefix('>')) {
+            (Some(value), _) => match value.strip_prefix('=') {
+                Some(value) => (Operator::Le, value),
+                None => (Operator::Lt, value),
+            },
+            (_, Some(value)) => match value.strip_prefix('=') {
+                Some(value) => (Operator::Ge, value),
+                None => (Operator::Gt, value),
+            },
+            _ => return Err(invalid()),
+        };
+
+        let metric = Metric::parse(&metric.to_lowercase()).ok_or_else(|| {
+            format!("Unknown threshold metric in {expression}, use p50..p99.9, min, max, mean, transport_error_rate, check_failure_rate, rps, requests or errors")
+        })?;
+
+        let limit = match metric {
+            _ if metric.is_latency() => parse_latency(value),
+            _ if metric.is_rate() => value.strip_suffix('%').unwrap_or(value).parse().ok(),
+            _ => value.parse().ok(),
+        }
+        .filter(|limit: &f64| limit.is_finite())
+        .ok_or_else(invalid)?;
+
+        Ok(Threshold {
+            expression: expression.trim().to_string(),
+            metric,
+            operator,
+            limit,
+        })
+    }
+
+    pub fn evaluate(&self, summary: &stats::Summary, elapsed: Duration) -> Evaluation<'_> {
+        let actual = self.metric.measure(summary, elapsed);
+
+        Evaluation {
+            threshold: self,
+            actual: actual.map(|actual| self.metric.format(actual)),
+            passed: actual.is_some_and(|actual| self.operator.holds(actual, self.limit)),
+        }
+    }
+}
+
+/// Parses a latency in microseconds, such as `300ms`, `1.5s` or `800us`. Plain numbers are milliseconds.
+fn parse_latency(input: &str) -> Option<f64> {
+    let (value, scale) = match input {
+        _ if input.ends_with("us") => (&input[..input.len() - 2], 1.0),
+        _ if input.ends_with("ms") => (&input[..input.len() - 2], 1_000.0),
+        _ if input.ends_with('s') => (&input[..input.len() - 1], 1_000_000.0),
+        _ => (input, 1_000.0),
+    };
+
+    value.parse::<f64>().ok().map(|value| value * scale)
+}
+
+/// Evaluates every --threshold against the totals of the run.
+pub fn evaluate<'a>(
+    config: &'a config::Config,
+    run: &process::RunResults,
+    elapsed: Duration,
+) -> Vec<Evaluation<'a>> {
+    config
+        .thresholds
+        .iter()
+        .map(|threshold| threshold.evaluate(&run.stats.total, elapsed))
+        .collect()
+}
+
+/// Prints the pass/fail table and returns true when every threshold passed.
+pub fn print(evaluations: &[Evaluation]) -> bool {
+    println!("*** Thresholds:");
+
+    for evaluation in evaluations.iter() {
+        println!(
+            "* [{}] {} : {}",
+            match evaluation.passed {
+                true => "PASS".green(),
+                false => "FAIL".red(),
+            },
+            evaluation.threshold.expression,
+            evaluation.actual.as_deref().unwrap_or("no value measured"),
+        );
+    }
+
+    let failed = evaluations.iter().filter(|e| !e.passed).count();
+
+    match failed {
+        0 => println!("{}\n", "*** All thresholds passed.".green()),
+        failed => println!(
+            "{}\n",
+            format!("*** {failed} of {} thresholds failed.", evaluations.len()).red()
+        ),
+    }
+
+    failed == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Metric, Operator, Threshold};
+    use std::time::Duration;
+
+    #[test]
+    fn parses_thresholds() {
+        let p95 = Threshold::parse("p95 < 300ms").unwrap();
+        assert_eq!(p95.metric, Metric::Percentile(95.0));
+        assert_eq!(p95.operator, Operator::Lt);
+        assert_eq!(p95.limit, 300_000.0);
+
+        let errors = Threshold::parse("transport_error_rate<=1.5%").unwrap();
+        assert_eq!(errors.operator, Operator::Le);
+        assert_eq!(errors.limit, 1.5);
+
+        let rps = Threshold::parse("rps>=200").unwrap();
+        assert_eq!(rps.operator, Operator::Ge);
+        assert_eq!(rps.limit, 200.0);
+
+        assert_eq!(Threshold::parse("p99.9<1.5s").unwrap().limit, 1_500_000.0);
+        assert_eq!(Threshold::parse("max<250").unwrap().limit, 250_000.0);
+
+        assert!(Threshold::parse("p95").is_err());
+        assert!(Threshold::parse("p0<1ms").is_err());
+        assert!(Threshold::parse("latency<1ms").is_err());
+        assert!(Threshold::parse("error_rate<1%").is_err());
+        assert!(Threshold::parse("rps>fast").is_err());
+    }
+
+    #[test]
+    fn evaluates_thresholds() {
+        let mut summary = crate::stats::Summary {
+            responses: 99,
+            ..Default::default()
+        };
+        summary.errors.insert(crate::stats::ErrorKind::Timeout, 1);
+
+        for millis in 1..=99 {
+            crate::stats::record_latency(&mut summary.latency, Duration::from_millis(millis));
+        }
+
+        let elapsed = Duration::from_secs(1);
+        let evaluate = |expression, summary| {
+            let threshold = Threshold::parse(expression).unwrap();
+            let evaluation = threshold.evaluate(summary, elapsed);
+            (evaluation.passed, evaluation.actual)
+        };
+
+        assert!(evaluate("p50<60ms", &summary).0);
+        assert!(!evaluate("p95<90ms", &summary).0);
+        assert!(evaluate("transport_error_rate<=1%", &summary).0);
+        assert!(!evaluate("transport_error_rate<1%", &summary).0);
+        assert_eq!(
+            evaluate("rps>98", &summary),
+            (true, Some("99.00".to_string()))
+        );
+        assert!(evaluate("requests>=100", &summary).0);
+
+        // Nothing to measure fails the threshold.
+        assert_eq!(
+            evaluate("p95<1s", &crate::stats::Summary::default()),
+            (false, None)
+        );
+    }
+}